@@ -0,0 +1,213 @@
+// Coloring of file names, driven by the LS_COLORS environment variable in
+// the format produced by dircolors(1), for example:
+//
+//     LS_COLORS='di=01;34:ln=01;36:ex=01;32:*.tar=01;31'
+//
+// Each two-letter key stands for a file type (or a special permission),
+// and each *pattern matches the end of a file name. The values are SGR
+// parameters, which end up between "\x1b[" and "m".
+
+use std::collections::HashMap;
+use std::fs::{FileType, Metadata};
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::fs::FileTypeExt;
+
+use crate::MyDirEntry;
+
+// The colors that take more than the file type to decide.
+const METADATA_KEYS: &[&str] = &["tw", "ow", "st", "su", "sg", "ex", "mh"];
+
+// Used when LS_COLORS is not set. These are the colors ls-rs has always
+// used: blue directories, green executables, cyan links and red broken
+// links.
+const DEFAULT_COLORS: &str = "di=34:ln=36:ex=32:or=31";
+
+#[derive(Debug)]
+pub struct Colors {
+    types: HashMap<String, String>,
+    // In the order they appear in LS_COLORS; later ones win.
+    patterns: Vec<(String, String)>,
+}
+
+impl Colors {
+    pub fn from_env() -> Colors {
+        match std::env::var("LS_COLORS") {
+            Ok(spec) if !spec.is_empty() => Colors::parse(&spec),
+            _ => Colors::parse(DEFAULT_COLORS),
+        }
+    }
+
+    // Entries that we don't understand are ignored, like GNU ls does.
+    pub fn parse(spec: &str) -> Colors {
+        let mut types = HashMap::new();
+        let mut patterns = Vec::new();
+        for item in spec.split(':') {
+            let Some((key, value)) = item.split_once('=') else {
+                continue;
+            };
+            if let Some(suffix) = key.strip_prefix('*') {
+                patterns.push((suffix.to_owned(), value.to_owned()));
+            } else if key.len() == 2 {
+                types.insert(key.to_owned(), value.to_owned());
+            }
+        }
+        Colors { types, patterns }
+    }
+
+    // Wraps the name in the escape sequences for the entry's color. Names
+    // of entries without a color are returned unchanged.
+    pub fn paint(&self, entry: &MyDirEntry, name: &[u8]) -> Vec<u8> {
+        match self.codes(entry) {
+            Some((start, end)) => [start.as_bytes(), name, end.as_bytes()].concat(),
+            None => name.to_vec(),
+        }
+    }
+
+    // The escape sequences that paint puts before and after the name, for
+    // when we need to know where exactly the name is in the output.
+    pub fn codes(&self, entry: &MyDirEntry) -> Option<(String, String)> {
+        let code = self.code_for(entry)?;
+        let start = format!("{}{}{}",
+            self.get("lc").unwrap_or("\x1b["), code, self.get("rc").unwrap_or("m"));
+        Some((start, self.end()))
+    }
+
+    fn end(&self) -> String {
+        match self.get("ec") {
+            Some(end) => end.to_owned(),
+            None => format!("{}{}{}",
+                self.get("lc").unwrap_or("\x1b["), self.get("rs").unwrap_or("0"),
+                self.get("rc").unwrap_or("m")),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.types.get(key).map(|value| value.as_str()).filter(|value| !value.is_empty())
+    }
+
+    // Whether some of the colors depend on more than the type of the file:
+    // on permission bits or the link count, which only a stat tells.
+    pub fn needs_metadata(&self) -> bool {
+        METADATA_KEYS.iter().any(|key| self.get(key).is_some())
+    }
+
+    fn code_for(&self, entry: &MyDirEntry) -> Option<&str> {
+        let file_type = entry.file_type().ok()?;
+        if entry.is_broken_link() {
+            return self.get("or").or_else(|| self.get("ln").filter(|code| *code != "target"));
+        }
+        if file_type.is_symlink() {
+            return match entry.target_metadata() {
+                // "ln=target" means: color the link like the file it points to.
+                Some(target) if self.get("ln") == Some("target") => {
+                    self.code_for_type(target.file_type(), || Some(target), &entry.file.to_string_lossy())
+                }
+                _ => self.get("ln"),
+            };
+        }
+        self.code_for_type(file_type, || entry.metadata().ok(), &entry.file.to_string_lossy())
+    }
+
+    // The order of the checks follows GNU ls: special permission bits take
+    // precedence over the plain file type, and the *patterns only apply to
+    // regular files that got no other color. We ask for the metadata only
+    // where a color depends on it.
+    fn code_for_type<'m>(&self, file_type: FileType, metadata: impl Fn() -> Option<&'m Metadata>,
+        file: &str) -> Option<&str>
+    {
+        let mode = |keys: &[&str]| {
+            if keys.iter().any(|key| self.get(key).is_some()) {
+                metadata().map_or(0, |metadata| metadata.st_mode())
+            } else {
+                0
+            }
+        };
+        if file_type.is_dir() {
+            let mode = mode(&["tw", "ow", "st"]);
+            let sticky = mode & libc::S_ISVTX != 0;
+            let other_writable = mode & libc::S_IWOTH != 0;
+            return match (sticky, other_writable) {
+                (true, true) => self.get("tw"),
+                (false, true) => self.get("ow"),
+                (true, false) => self.get("st"),
+                (false, false) => None,
+            }.or_else(|| self.get("di"));
+        }
+        if file_type.is_fifo() {
+            return self.get("pi");
+        }
+        if file_type.is_socket() {
+            return self.get("so");
+        }
+        if file_type.is_block_device() {
+            return self.get("bd");
+        }
+        if file_type.is_char_device() {
+            return self.get("cd");
+        }
+        let mode = mode(&["su", "sg", "ex"]);
+        let special = if mode & libc::S_ISUID != 0 {
+            self.get("su")
+        } else if mode & libc::S_ISGID != 0 {
+            self.get("sg")
+        } else {
+            None
+        };
+        let executable = mode & libc::S_IXUSR != 0;
+        let hard_linked = || self.get("mh").is_some() && metadata().is_some_and(|m| m.st_nlink() > 1);
+        special
+            .or_else(|| if executable { self.get("ex") } else { None })
+            .or_else(|| if hard_linked() { self.get("mh") } else { None })
+            .or_else(|| self.code_for_name(file))
+            .or_else(|| self.get("fi"))
+    }
+
+    // An exact match on the suffix wins over a case-insensitive one.
+    fn code_for_name(&self, file: &str) -> Option<&str> {
+        let exact = self.patterns.iter().rev()
+            .find(|(suffix, _)| file.ends_with(suffix.as_str()));
+        let found = exact.or_else(|| {
+            let file = file.to_lowercase();
+            self.patterns.iter().rev()
+                .find(|(suffix, _)| file.ends_with(&suffix.to_lowercase()))
+        });
+        found.map(|(_, code)| code.as_str()).filter(|code| !code.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_keeps_types_and_patterns() {
+        let colors = Colors::parse("di=01;34:ln=target:*.tar=01;31:*.TAR=32:bogus:xyz=1");
+        assert_eq!(colors.get("di"), Some("01;34"));
+        assert_eq!(colors.get("ln"), Some("target"));
+        assert_eq!(colors.get("xyz"), None);
+        assert_eq!(colors.patterns.len(), 2);
+    }
+
+    #[test]
+    fn empty_values_count_as_unset() {
+        let colors = Colors::parse("di=:fi=0");
+        assert_eq!(colors.get("di"), None);
+        assert_eq!(colors.get("fi"), Some("0"));
+    }
+
+    #[test]
+    fn exact_suffixes_win_over_case_insensitive_ones() {
+        let colors = Colors::parse("*.tar=31:*.TAR=32:*.gz=33");
+        assert_eq!(colors.code_for_name("a.tar"), Some("31"));
+        assert_eq!(colors.code_for_name("a.TAR"), Some("32"));
+        assert_eq!(colors.code_for_name("a.GZ"), Some("33"));
+        assert_eq!(colors.code_for_name("a.zip"), None);
+    }
+
+    #[test]
+    fn end_sequence_follows_the_spec() {
+        assert_eq!(Colors::parse("").end(), "\x1b[0m");
+        assert_eq!(Colors::parse("ec=\x1b[m").end(), "\x1b[m");
+        assert_eq!(Colors::parse("lc=<:rs=x:rc=>").end(), "<x>");
+    }
+}
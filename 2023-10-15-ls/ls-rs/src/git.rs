@@ -0,0 +1,143 @@
+// The --git column: two characters telling where a file stands in the git
+// repository around it, the first for the index (staged changes), the
+// second for the working tree. As in exa: N new, M modified, D deleted,
+// R renamed, T type changed, I ignored, U conflicted and - for nothing
+// to report. A directory shows what is going on inside it.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use git2::{Repository, Status, StatusOptions};
+
+struct Repo {
+    workdir: PathBuf,
+    // Paths relative to workdir. Git reports an untracked or ignored
+    // directory as a whole, without looking inside; for those the flag is
+    // set.
+    statuses: Vec<(PathBuf, bool, Status)>,
+}
+
+thread_local! {
+    // Asking git for the status of a large working tree takes a while, so
+    // we do it once per repository, and remember which repository (if any)
+    // each directory belongs to.
+    static DIRECTORIES: RefCell<HashMap<PathBuf, Option<Rc<Repo>>>> = RefCell::new(HashMap::new());
+    static REPOSITORIES: RefCell<HashMap<PathBuf, Rc<Repo>>> = RefCell::new(HashMap::new());
+}
+
+pub fn status(path: &Path, is_dir: bool) -> String {
+    let Some(path) = absolute_path(path) else {
+        return "--".to_owned();
+    };
+    // The top of the working tree is the one directory whose parent is
+    // outside the repository.
+    let repo = path.parent().and_then(repository)
+        .or_else(|| if is_dir { repository(&path) } else { None });
+    let Some(repo) = repo else {
+        return "--".to_owned();
+    };
+    let Ok(relative) = path.strip_prefix(&repo.workdir) else {
+        return "--".to_owned();
+    };
+    let mut combined = Status::empty();
+    // What git says about an untracked or ignored directory around the
+    // file; it only counts when nothing more specific does.
+    let mut inherited = Status::empty();
+    for (path, whole_directory, status) in &repo.statuses {
+        if path == relative || (is_dir && path.starts_with(relative) && !status.is_ignored()) {
+            combined |= *status;
+        } else if *whole_directory && relative.starts_with(path) {
+            inherited = *status;
+        }
+    }
+    if combined.is_empty() {
+        combined = inherited;
+    }
+    format!("{}{}", index_char(combined), working_tree_char(combined))
+}
+
+// Without following the file itself if it's a symbolic link: git tracks
+// the link, not what it points to.
+fn absolute_path(path: &Path) -> Option<PathBuf> {
+    match path.file_name() {
+        Some(name) if name != OsStr::new("..") => {
+            let parent = match path.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            Some(fs::canonicalize(parent).ok()?.join(name))
+        }
+        _ => fs::canonicalize(path).ok(),
+    }
+}
+
+fn repository(dir: &Path) -> Option<Rc<Repo>> {
+    if let Some(repo) = DIRECTORIES.with(|cache| cache.borrow().get(dir).cloned()) {
+        return repo;
+    }
+    let repo = open_repository(dir);
+    DIRECTORIES.with(|cache| cache.borrow_mut().insert(dir.to_path_buf(), repo.clone()));
+    repo
+}
+
+fn open_repository(dir: &Path) -> Option<Rc<Repo>> {
+    let repository = Repository::discover(dir).ok()?;
+    let workdir = fs::canonicalize(repository.workdir()?).ok()?;
+    if let Some(repo) = REPOSITORIES.with(|cache| cache.borrow().get(&workdir).cloned()) {
+        return Some(repo);
+    }
+    let mut options = StatusOptions::new();
+    options.include_untracked(true).include_ignored(true).recurse_untracked_dirs(true);
+    let statuses = repository.statuses(Some(&mut options)).ok()?
+        .iter()
+        .map(|entry| {
+            let path = entry.path_bytes();
+            let whole_directory = path.ends_with(b"/");
+            (PathBuf::from(OsStr::from_bytes(path)), whole_directory, entry.status())
+        })
+        .collect();
+    let repo = Rc::new(Repo { workdir: workdir.clone(), statuses });
+    REPOSITORIES.with(|cache| cache.borrow_mut().insert(workdir, repo.clone()));
+    Some(repo)
+}
+
+fn index_char(status: Status) -> char {
+    if status.is_index_new() {
+        'N'
+    } else if status.is_index_modified() {
+        'M'
+    } else if status.is_index_deleted() {
+        'D'
+    } else if status.is_index_renamed() {
+        'R'
+    } else if status.is_index_typechange() {
+        'T'
+    } else {
+        '-'
+    }
+}
+
+fn working_tree_char(status: Status) -> char {
+    if status.is_wt_new() {
+        'N'
+    } else if status.is_wt_modified() {
+        'M'
+    } else if status.is_wt_deleted() {
+        'D'
+    } else if status.is_wt_renamed() {
+        'R'
+    } else if status.is_wt_typechange() {
+        'T'
+    } else if status.is_ignored() {
+        'I'
+    } else if status.is_conflicted() {
+        'U'
+    } else {
+        '-'
+    }
+}
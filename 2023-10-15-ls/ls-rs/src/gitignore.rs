@@ -0,0 +1,255 @@
+// The subset of gitignore(5) that we need to tell which entries of a
+// directory git would ignore: the .gitignore files from the top of the
+// repository down to the directory, and .git/info/exclude. Global
+// excludes (core.excludesFile) are not read.
+
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::fnmatch;
+
+#[derive(Debug)]
+struct Rule {
+    // The pattern split at '/'; "**" stays a segment of its own.
+    segments: Vec<CString>,
+    negated: bool,
+    directory_only: bool,
+    // A pattern with a slash at the beginning or in the middle matches
+    // paths relative to its .gitignore; one without matches a name at any
+    // depth.
+    anchored: bool,
+    // The directory of the .gitignore the rule came from.
+    base: PathBuf,
+}
+
+#[derive(Debug)]
+pub struct GitIgnore {
+    // Ordered from the lowest to the highest precedence: the last rule
+    // that matches decides.
+    rules: Vec<Rule>,
+    root: PathBuf,
+    // The directory whose entries we are asked about, without symbolic
+    // links, so that it lies below root.
+    dir: PathBuf,
+}
+
+impl GitIgnore {
+    // Collects the rules that apply to the entries of the directory. We
+    // walk up until we find the top of the repository (the directory with
+    // .git in it). Outside a repository git ignores nothing, so then there
+    // are no rules, whatever .gitignore files lie on the way to the root.
+    pub fn for_directory(dir: &Path) -> GitIgnore {
+        let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf());
+        let mut dirs: Vec<&Path> = Vec::new();
+        let mut in_repository = false;
+        for ancestor in dir.ancestors() {
+            dirs.push(ancestor);
+            if ancestor.join(".git").exists() {
+                in_repository = true;
+                break;
+            }
+        }
+        if !in_repository {
+            return GitIgnore { rules: Vec::new(), root: dir.clone(), dir };
+        }
+        dirs.reverse();
+        let root = dirs[0].to_path_buf();
+        let mut rules = Vec::new();
+        read_rules(&root.join(".git/info/exclude"), &root, &mut rules);
+        for ancestor in dirs {
+            read_rules(&ancestor.join(".gitignore"), ancestor, &mut rules);
+        }
+        GitIgnore { rules, root, dir }
+    }
+
+    // Git doesn't look inside an ignored directory, so everything below
+    // one is ignored too, whatever the rules say about it.
+    pub fn is_ignored(&self, file: &OsStr, is_dir: bool) -> bool {
+        let path = self.dir.join(file);
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        let mut current = self.root.clone();
+        let components: Vec<&OsStr> = relative.components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        for (index, name) in components.iter().enumerate() {
+            current.push(name);
+            let last = index == components.len() - 1;
+            if self.matches(&current, if last { is_dir } else { true }) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.negated != ignored {
+                // The rule can't change the outcome.
+                continue;
+            }
+            if rule.directory_only && !is_dir {
+                continue;
+            }
+            let Ok(relative) = path.strip_prefix(&rule.base) else {
+                continue;
+            };
+            let names: Vec<&[u8]> = relative.components()
+                .map(|component| component.as_os_str().as_encoded_bytes())
+                .collect();
+            let matched = if rule.anchored {
+                match_segments(&rule.segments, &names)
+            } else {
+                names.last().is_some_and(|name| match_segment(&rule.segments[0], name))
+            };
+            if matched {
+                ignored = !rule.negated;
+            }
+        }
+        ignored
+    }
+}
+
+fn read_rules(file: &Path, base: &Path, rules: &mut Vec<Rule>) {
+    let Ok(contents) = fs::read(file) else {
+        return;
+    };
+    for line in contents.split(|byte| *byte == b'\n') {
+        if let Some(rule) = parse_rule(line, base) {
+            rules.push(rule);
+        }
+    }
+}
+
+fn parse_rule(line: &[u8], base: &Path) -> Option<Rule> {
+    let mut line = line.strip_suffix(b"\r").unwrap_or(line);
+    // Trailing spaces don't count, unless escaped with a backslash.
+    while line.ends_with(b" ") && !line.ends_with(b"\\ ") {
+        line = &line[..line.len() - 1];
+    }
+    if line.is_empty() || line.starts_with(b"#") {
+        return None;
+    }
+    let negated = line.starts_with(b"!");
+    if negated {
+        line = &line[1..];
+    }
+    if line.starts_with(b"\\#") || line.starts_with(b"\\!") {
+        line = &line[1..];
+    }
+    let directory_only = line.ends_with(b"/");
+    if directory_only {
+        line = &line[..line.len() - 1];
+    }
+    let anchored = line.contains(&b'/');
+    let line = line.strip_prefix(b"/").unwrap_or(line);
+    if line.is_empty() {
+        return None;
+    }
+    let segments = line.split(|byte| *byte == b'/')
+        .map(|segment| CString::new(segment).ok())
+        .collect::<Option<Vec<CString>>>()?;
+    Some(Rule { segments, negated, directory_only, anchored, base: base.to_path_buf() })
+}
+
+// "**" matches any number of directories, including none.
+fn match_segments(segments: &[CString], names: &[&[u8]]) -> bool {
+    match segments.split_first() {
+        None => names.is_empty(),
+        Some((first, rest)) if first.as_bytes() == b"**" => {
+            (0..=names.len()).any(|skip| match_segments(rest, &names[skip..]))
+        }
+        Some((first, rest)) => {
+            !names.is_empty() && match_segment(first, names[0]) && match_segments(rest, &names[1..])
+        }
+    }
+}
+
+fn match_segment(pattern: &CString, name: &[u8]) -> bool {
+    let Ok(name) = CString::new(name) else {
+        return false;
+    };
+    unsafe { fnmatch(pattern.as_ptr(), name.as_ptr(), 0) == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(line: &str) -> Rule {
+        parse_rule(line.as_bytes(), Path::new("/repo")).unwrap()
+    }
+
+    fn segments(pattern: &str) -> Vec<CString> {
+        pattern.split('/').map(|segment| CString::new(segment).unwrap()).collect()
+    }
+
+    fn names(path: &str) -> Vec<&[u8]> {
+        path.split('/').map(str::as_bytes).collect()
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_no_rules() {
+        let base = Path::new("/repo");
+        assert!(parse_rule(b"", base).is_none());
+        assert!(parse_rule(b"   ", base).is_none());
+        assert!(parse_rule(b"# comment", base).is_none());
+        assert!(parse_rule(b"/", base).is_none());
+    }
+
+    #[test]
+    fn rule_flags() {
+        let target = rule("target/");
+        assert!(target.directory_only && !target.anchored && !target.negated);
+        let keep = rule("!keep.log");
+        assert!(keep.negated && !keep.directory_only);
+        assert!(rule("/build").anchored);
+        assert!(rule("doc/*.html").anchored);
+        assert_eq!(rule("/build").segments, segments("build"));
+    }
+
+    #[test]
+    fn escapes_and_trailing_spaces() {
+        assert_eq!(rule("\\#hash").segments, segments("#hash"));
+        assert!(!rule("\\!bang").negated);
+        assert_eq!(rule("name  \r").segments, segments("name"));
+        assert_eq!(rule("name\\ ").segments, segments("name\\ "));
+    }
+
+    #[test]
+    fn segments_match_one_name_each() {
+        assert!(match_segments(&segments("doc/*.html"), &names("doc/index.html")));
+        assert!(!match_segments(&segments("doc/*.html"), &names("doc/api/index.html")));
+        assert!(!match_segments(&segments("doc"), &names("doc/index.html")));
+    }
+
+    #[test]
+    fn double_star_matches_any_depth() {
+        let pattern = segments("**/logs/*.log");
+        assert!(match_segments(&pattern, &names("logs/a.log")));
+        assert!(match_segments(&pattern, &names("x/y/logs/a.log")));
+        assert!(!match_segments(&pattern, &names("x/logs/a.txt")));
+        let pattern = segments("build/**");
+        assert!(match_segments(&pattern, &names("build/a/b")));
+    }
+
+    #[test]
+    fn nothing_is_ignored_outside_a_repository() {
+        let top = std::env::temp_dir().join(format!("ls-rs-gitignore-{}", std::process::id()));
+        let project = top.join("project");
+        fs::create_dir_all(&project).unwrap();
+        fs::write(top.join(".gitignore"), "*.log\n").unwrap();
+        let outside = GitIgnore::for_directory(&project);
+        fs::create_dir(top.join(".git")).unwrap();
+        let inside = GitIgnore::for_directory(&project);
+        fs::remove_dir_all(&top).unwrap();
+        assert!(!outside.is_ignored(OsStr::new("a.log"), false));
+        assert!(inside.is_ignored(OsStr::new("a.log"), false));
+    }
+}
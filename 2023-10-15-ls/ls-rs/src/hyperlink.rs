@@ -0,0 +1,81 @@
+// --hyperlink: OSC 8 escape sequences around a name, which terminals that
+// understand them turn into a link to file://host/path. Like the colors,
+// they take no room on the screen.
+
+use std::env;
+use std::ffi::CStr;
+use std::fmt::Write;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+// Closes whatever link start opened.
+pub const END: &str = "\x1b]8;;\x07";
+
+pub fn start(path: &Path) -> String {
+    format!("\x1b]8;;{}\x07", url(path))
+}
+
+pub fn wrap(path: &Path, text: &[u8]) -> Vec<u8> {
+    [start(path).as_bytes(), text, END.as_bytes()].concat()
+}
+
+fn url(path: &Path) -> String {
+    let path = resolve(path);
+    let mut url = format!("file://{}", host_name());
+    for byte in path.as_os_str().as_bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                url.push(*byte as char);
+            }
+            _ => {
+                let _ = write!(url, "%{:02x}", byte);
+            }
+        }
+    }
+    url
+}
+
+// The path without symbolic links, as GNU ls does it, so that a link to a
+// file opens the file. A broken link has nothing to resolve to, so we
+// resolve the directory it is in.
+fn resolve(path: &Path) -> PathBuf {
+    if let Ok(path) = fs::canonicalize(path) {
+        return path;
+    }
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    match (fs::canonicalize(parent), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => absolute(path),
+    }
+}
+
+// The last resort, without the "." components that joining the path to
+// the current directory would leave in.
+fn absolute(path: &Path) -> PathBuf {
+    let path = match env::current_dir() {
+        Ok(dir) => dir.join(path),
+        Err(_) => path.to_path_buf(),
+    };
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+fn host_name() -> &'static str {
+    static HOST_NAME: OnceLock<String> = OnceLock::new();
+    HOST_NAME.get_or_init(|| {
+        let mut buffer = [0u8; 256];
+        let result = unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) };
+        if result != 0 {
+            return String::new();
+        }
+        CStr::from_bytes_until_nul(&buffer)
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    })
+}
@@ -0,0 +1,1189 @@
+// The listing engine of ls-rs: which entries a directory has, in which
+// order, and (in the render module) how to print them. The binary parses
+// the command line into LsOptions and drives these; other tools can do
+// the same.
+
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::fs::{FileType, Metadata};
+use std::os::linux::fs::MetadataExt;
+use chrono::format::{Item, StrftimeItems};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::{self, AtomicUsize};
+use std::thread;
+
+pub mod colors;
+mod git;
+mod gitignore;
+mod hyperlink;
+pub mod quoting;
+pub mod render;
+mod xattr;
+use colors::Colors;
+use gitignore::GitIgnore;
+use quoting::QuotingStyle;
+use render::entry_time;
+pub use render::Renderer;
+
+// How the short format (no -l) arranges names on the screen.
+#[derive(Debug, PartialEq)]
+pub enum Layout {
+    OneLine,
+    Columns,
+    Across,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SortBy {
+    Name,
+    Time,
+    Size,
+    Extension,
+    Version,
+    // -U: keep the order in which read_dir returned the entries.
+    Unsorted,
+}
+
+// Which type indicators (-F, --file-type, -p) we append to names.
+#[derive(Debug, PartialEq)]
+pub enum Indicators {
+    None,
+    // -p: a slash after directories.
+    Slash,
+    // --file-type: like -F, but without '*' for executables.
+    FileType,
+    // -F: '/' directory, '*' executable, '@' symbolic link, '|' FIFO,
+    // '=' socket.
+    Classify,
+}
+
+// Machine-readable output: a single JSON array, or one JSON object per
+// line (NDJSON), which a script can process while we are still listing.
+#[derive(Debug, PartialEq)]
+pub enum JsonFormat {
+    Array,
+    Lines,
+}
+
+// Which of the file's timestamps we show and sort by.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeField {
+    Modification,
+    Access,
+    // ctime, the last change of the inode (permissions, owner, links...).
+    Change,
+    Birth,
+}
+
+// strftime formats for the time column: one for files modified within
+// the last six months, one for older files (and for future timestamps).
+#[derive(Debug, Clone)]
+pub struct TimeStyle {
+    pub recent: String,
+    pub old: String,
+}
+
+impl TimeStyle {
+    // Accepts the styles of GNU ls. "+FORMAT" is a strftime format; a newline
+    // in it separates the format for older files from the one for recent
+    // files, as in '+%Y-%m-%d\n%m-%d %H:%M'.
+    pub fn parse(arg: &str) -> Result<TimeStyle, String> {
+        let style = |recent: &str, old: &str| Ok(TimeStyle { recent: recent.to_owned(), old: old.to_owned() });
+        match arg {
+            "full-iso" => style("%Y-%m-%d %H:%M:%S%.9f %z", "%Y-%m-%d %H:%M:%S%.9f %z"),
+            "long-iso" => style("%Y-%m-%d %H:%M", "%Y-%m-%d %H:%M"),
+            "iso" => style("%m-%d %H:%M", "%Y-%m-%d "),
+            "locale" => style("%b %e %H:%M", "%b %e  %Y"),
+            _ => {
+                let Some(format) = arg.strip_prefix('+') else {
+                    return Err(format!("invalid time style format '{}'", arg));
+                };
+                // chrono panics when asked to print with a broken format, so
+                // we check it here, while we can still complain nicely.
+                if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+                    return Err(format!("invalid time style format '{}'", arg));
+                }
+                match format.split_once('\n') {
+                    Some((old, recent)) => style(recent, old),
+                    None => style(format, format),
+                }
+            }
+        }
+    }
+}
+
+// The default of GNU ls in the C locale.
+impl Default for TimeStyle {
+    fn default() -> TimeStyle {
+        TimeStyle::parse("locale").unwrap()
+    }
+}
+
+// How we print file sizes and the "total" line of the long format.
+#[derive(Debug, Clone)]
+pub enum SizeStyle {
+    // Sizes in bytes, the total in 1024-byte blocks.
+    Default,
+    // -h and --si: scaled to powers of 1024 or 1000, with a unit suffix.
+    Human(u64),
+    // --block-size: the number of blocks (rounded up), followed by the
+    // suffix (empty if the block size was given as a plain number).
+    Blocks(u64, String),
+}
+
+impl SizeStyle {
+    // Parses the argument of --block-size: an optional number followed by an
+    // optional unit, K, M, G... (powers of 1024), KiB, MiB... (likewise) or
+    // KB, MB... (powers of 1000). The unit is printed after each size only
+    // when the number is omitted, so "M" gives "3M" but "1M" gives "3".
+    pub fn parse_block_size(arg: &str) -> Result<SizeStyle, String> {
+        let invalid = || format!("invalid block size '{}'", arg);
+        let split = arg.find(|c: char| !c.is_ascii_digit()).unwrap_or(arg.len());
+        let (number, unit) = arg.split_at(split);
+        let multiplier: u64 = if number.is_empty() {
+            1
+        } else {
+            number.parse().map_err(|_| invalid())?
+        };
+        let mut chars = unit.chars();
+        let (scale, suffix) = match chars.next() {
+            None => (1, String::new()),
+            Some(letter) => {
+                let power = match letter.to_ascii_uppercase() {
+                    'K' => 1,
+                    'M' => 2,
+                    'G' => 3,
+                    'T' => 4,
+                    'P' => 5,
+                    'E' => 6,
+                    _ => return Err(invalid()),
+                };
+                let base: u64 = match chars.as_str() {
+                    "" | "iB" => 1024,
+                    "B" => 1000,
+                    _ => return Err(invalid()),
+                };
+                // By convention the SI kilo is a lowercase k.
+                let suffix = if unit == "KB" { "kB".to_owned() } else { unit.to_owned() };
+                (base.pow(power), suffix)
+            }
+        };
+        let block_size = multiplier.checked_mul(scale).filter(|size| *size > 0).ok_or_else(invalid)?;
+        if number.is_empty() {
+            Ok(SizeStyle::Blocks(block_size, suffix))
+        } else {
+            Ok(SizeStyle::Blocks(block_size, String::new()))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LsOptions {
+    pub long: bool,
+    pub layout: Layout,
+    pub width: usize,
+    pub all: bool,
+    pub almost_all: bool,
+    // --ignore (and -B) patterns apply even with -a; --hide patterns only
+    // without -a and -A.
+    pub ignore: Vec<CString>,
+    pub hide: Vec<CString>,
+    // Hide the entries that git ignores; with -a they are shown, and the
+    // long format marks them.
+    pub gitignore: bool,
+    pub recursive: bool,
+    // Some for --tree: how many levels to descend, usize::MAX for all.
+    pub tree: Option<usize>,
+    pub dereference: bool,
+    pub numeric_ids: bool,
+    // -i and -s: the inode number and the allocated size go in front of
+    // each entry, in every format.
+    pub inode: bool,
+    pub blocks: bool,
+    // -Z: the SELinux security context.
+    pub context: bool,
+    // --git: a column with the git status in the long format.
+    pub git: bool,
+    pub sort: SortBy,
+    pub reverse: bool,
+    pub group_directories_first: bool,
+    pub size_style: SizeStyle,
+    // None when the output is not to be colored.
+    pub colors: Option<Colors>,
+    pub directory: bool,
+    pub indicators: Indicators,
+    pub quoting_style: QuotingStyle,
+    pub hide_control: bool,
+    pub time_field: TimeField,
+    pub time_style: TimeStyle,
+    pub json: Option<JsonFormat>,
+    // --zero: end each entry with a NUL instead of a newline.
+    pub zero: bool,
+    // --dired: for Emacs, indent the long format and append where the
+    // names are, as byte offsets.
+    pub dired: bool,
+    // --hyperlink: make the names links to the files.
+    pub hyperlink: bool,
+}
+
+// What plain `ls-rs` does when its output doesn't go to a terminal: one
+// name per line, sorted by name, without dotfiles, colors or quoting.
+impl Default for LsOptions {
+    fn default() -> LsOptions {
+        LsOptions {
+            long: false,
+            layout: Layout::OneLine,
+            width: 80,
+            all: false,
+            almost_all: false,
+            ignore: Vec::new(),
+            hide: Vec::new(),
+            gitignore: false,
+            recursive: false,
+            tree: None,
+            dereference: false,
+            numeric_ids: false,
+            inode: false,
+            blocks: false,
+            context: false,
+            git: false,
+            sort: SortBy::Name,
+            reverse: false,
+            group_directories_first: false,
+            size_style: SizeStyle::Default,
+            colors: None,
+            directory: false,
+            indicators: Indicators::None,
+            quoting_style: QuotingStyle::Literal,
+            hide_control: false,
+            time_field: TimeField::Modification,
+            time_style: TimeStyle::default(),
+            json: None,
+            zero: false,
+            dired: false,
+            hyperlink: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MyDirEntry {
+    // OsString rather than String: a file name doesn't have to be valid
+    // UTF-8, and we don't want such files to disappear from the listing.
+    pub file: OsString,
+    pub path: PathBuf,
+    // Read when the entry is created, except by a Lister whose options
+    // don't look at the metadata at all (see Lister::needs_metadata).
+    stat: OnceLock<Stat>,
+    // What readdir told us about the type of an entry we haven't statted
+    // (d_type), if anything. It describes the link, not its target.
+    file_type: Option<FileType>,
+    dereference: bool,
+    // Set for the entries of a directory listed with --gitignore.
+    pub ignored: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Stat {
+    metadata: Metadata,
+    // For a symbolic link: the path it contains, and the metadata of the
+    // file it points to (None if the link is broken).
+    target: Option<PathBuf>,
+    target_metadata: Option<Metadata>,
+}
+
+impl Stat {
+    // fs::symlink_metadata (lstat) describes the link itself, while
+    // fs::metadata (stat) follows it. We start from the link, and only
+    // replace it with the target's metadata when asked to dereference; a
+    // broken link then can't be accessed, as with GNU ls -L.
+    fn read(path: &Path, dereference: bool) -> io::Result<Stat> {
+        let metadata = fs::symlink_metadata(path)?;
+        if !metadata.is_symlink() {
+            return Ok(Stat { metadata, target: None, target_metadata: None });
+        }
+        if dereference {
+            let metadata = fs::metadata(path)?;
+            return Ok(Stat { metadata, target: None, target_metadata: None });
+        }
+        let target_metadata = fs::metadata(path).ok();
+        let target = fs::read_link(path).ok();
+        Ok(Stat { metadata, target, target_metadata })
+    }
+}
+
+impl MyDirEntry {
+    pub fn new(file: OsString, dereference: bool) -> io::Result<MyDirEntry> {
+        let path = PathBuf::from(&file);
+        MyDirEntry::with_path(file, path, dereference)
+    }
+
+    // A file given on the command line. Like GNU ls, we follow a symbolic
+    // link there only if it points to a directory (which we then list),
+    // when follow_to_dir is set; any other link is shown as the link.
+    pub fn new_operand(file: OsString, follow_to_dir: bool) -> io::Result<MyDirEntry> {
+        let entry = MyDirEntry::new(file, false)?;
+        if follow_to_dir && entry.target_metadata().is_some_and(|m| m.is_dir()) {
+            return MyDirEntry::new(entry.file, true);
+        }
+        Ok(entry)
+    }
+
+    // Entries read from a directory are displayed by their file name, but
+    // we keep the full path so that we can stat them and descend into them.
+    pub fn new_from_path(path: PathBuf, dereference: bool) -> io::Result<MyDirEntry> {
+        let file = file_name(&path);
+        MyDirEntry::with_path(file, path, dereference)
+    }
+
+    pub fn with_path(file: OsString, path: PathBuf, dereference: bool) -> io::Result<MyDirEntry> {
+        let stat = Stat::read(&path, dereference)?;
+        Ok(MyDirEntry {
+            file, path, stat: OnceLock::from(stat), file_type: None, dereference, ignored: false,
+        })
+    }
+
+    // An entry whose metadata nobody is going to ask for, with the type if
+    // readdir gave us one. Should somebody ask anyway, we stat the file
+    // then.
+    fn without_metadata(path: PathBuf, file_type: Option<FileType>, dereference: bool) -> MyDirEntry {
+        let file = file_name(&path);
+        MyDirEntry { file, path, stat: OnceLock::new(), file_type, dereference, ignored: false }
+    }
+
+    // The file may have gone by the time a late stat comes. Then there is
+    // nothing to show but the name, and the accessors below make do.
+    fn stat(&self) -> io::Result<&Stat> {
+        if let Some(stat) = self.stat.get() {
+            return Ok(stat);
+        }
+        let stat = Stat::read(&self.path, self.dereference)?;
+        Ok(self.stat.get_or_init(|| stat))
+    }
+
+    pub fn metadata(&self) -> io::Result<&Metadata> {
+        self.stat().map(|stat| &stat.metadata)
+    }
+
+    // Without a stat if we can: the type from readdir will do, except for
+    // a link that we are to follow (-L).
+    pub fn file_type(&self) -> io::Result<FileType> {
+        if self.stat.get().is_none() {
+            if let Some(file_type) = self.file_type {
+                if !(self.dereference && file_type.is_symlink()) {
+                    return Ok(file_type);
+                }
+            }
+        }
+        Ok(self.metadata()?.file_type())
+    }
+
+    // Only a symbolic link has a target, which saves a stat for the rest.
+    pub fn target(&self) -> Option<&Path> {
+        if !self.is_symlink() {
+            return None;
+        }
+        self.stat().ok()?.target.as_deref()
+    }
+
+    pub fn target_metadata(&self) -> Option<&Metadata> {
+        if !self.is_symlink() {
+            return None;
+        }
+        self.stat().ok()?.target_metadata.as_ref()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type().is_ok_and(|t| t.is_dir())
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type().is_ok_and(|t| t.is_symlink())
+    }
+
+    pub fn is_dir_or_link_to_dir(&self) -> bool {
+        self.is_dir() || self.target_metadata().is_some_and(|m| m.is_dir())
+    }
+
+    pub fn is_dot_or_dotdot(&self) -> bool {
+        self.file == "." || self.file == ".."
+    }
+
+    pub fn is_broken_link(&self) -> bool {
+        self.is_symlink() && self.target_metadata().is_none()
+    }
+
+    // The extended attributes of whatever the metadata describes: the
+    // link itself, or (with -L) the file it points to.
+    pub fn attribute_names(&self) -> Vec<OsString> {
+        xattr::list(&self.path, !self.is_symlink())
+    }
+
+    pub fn security_context(&self) -> Option<String> {
+        xattr::security_context(&self.path, !self.is_symlink())
+    }
+
+    // Device and inode number uniquely identify a directory, no matter
+    // which path (or symbolic link) we used to reach it.
+    pub fn dev_ino(&self) -> io::Result<(u64, u64)> {
+        let metadata = self.metadata()?;
+        Ok((metadata.st_dev(), metadata.st_ino()))
+    }
+}
+
+fn file_name(path: &Path) -> OsString {
+    path.file_name().unwrap_or(path.as_os_str()).to_os_string()
+}
+
+// A problem with a single entry, which doesn't stop us from listing the
+// rest of the directory.
+#[derive(Debug)]
+pub enum EntryError {
+    // read_dir failed part of the way through the directory.
+    Reading(io::Error),
+    // The entry is there, but we can't stat it; it may have been removed
+    // in the meantime.
+    Access(PathBuf, io::Error),
+}
+
+#[derive(Debug)]
+pub struct Listing {
+    // The entries that the options let through, sorted.
+    pub entries: Vec<MyDirEntry>,
+    pub errors: Vec<EntryError>,
+}
+
+// Reads directories the way the options say: which entries to show, in
+// which order, and with what metadata. Printing them is up to a Renderer.
+//
+//     let lister = Lister::new(LsOptions { all: true, ..LsOptions::default() });
+//     let listing = lister.list(Path::new("."))?;
+//     let mut renderer = Renderer::new(lister.options(), io::stdout().lock());
+//     renderer.show_listing(&listing.entries)?;
+//
+// Lister::walk and Lister::tree go down into the subdirectories, as -R
+// and --tree do.
+#[derive(Debug)]
+pub struct Lister {
+    options: LsOptions,
+}
+
+impl Lister {
+    pub fn new(options: LsOptions) -> Lister {
+        Lister { options }
+    }
+
+    pub fn options(&self) -> &LsOptions {
+        &self.options
+    }
+
+    // In the order of a listing, for entries that don't come from one,
+    // like the files given on the command line.
+    pub fn sort(&self, entries: &mut [MyDirEntry]) {
+        sort_entries(entries, &self.options);
+    }
+
+    // Fails only if the directory can't be opened at all.
+    pub fn list(&self, dir: &Path) -> io::Result<Listing> {
+        let options = &self.options;
+        let mut entries: Vec<MyDirEntry> = Vec::new();
+        let mut errors = Vec::new();
+        let mut paths = Vec::new();
+        let mut file_types = Vec::new();
+        for dir_entry in fs::read_dir(dir)? {
+            match dir_entry {
+                Ok(dir_entry) => {
+                    if self.needs_file_type() {
+                        file_types.push(dir_entry.file_type().ok());
+                    }
+                    paths.push(dir_entry.path());
+                }
+                Err(error) => {
+                    errors.push(EntryError::Reading(error));
+                    break;
+                }
+            }
+        }
+        // read_dir never returns . and .., so for -a we have to add them
+        // ourselves. They go first, where readdir has them on most file
+        // systems, which is what -f and -U show.
+        if options.all {
+            for name in DOTS {
+                let path = dir.join(name);
+                match MyDirEntry::with_path(OsString::from(name), path.clone(), options.dereference) {
+                    Ok(entry) => entries.push(entry),
+                    Err(error) => errors.push(EntryError::Access(path, error)),
+                }
+            }
+        }
+        if self.needs_metadata() {
+            for (path, entry) in stat_entries(&paths, options.dereference) {
+                match entry {
+                    Ok(entry) => entries.push(entry),
+                    Err(error) => errors.push(EntryError::Access(path.clone(), error)),
+                }
+            }
+        } else {
+            file_types.resize(paths.len(), None);
+            entries.extend(paths.into_iter().zip(file_types)
+                .map(|(path, file_type)| MyDirEntry::without_metadata(path, file_type, options.dereference)));
+        }
+        if options.gitignore {
+            let gitignore = GitIgnore::for_directory(dir);
+            for entry in entries.iter_mut().filter(|entry| !entry.is_dot_or_dotdot()) {
+                entry.ignored = gitignore.is_ignored(&entry.file, entry.is_dir());
+            }
+        }
+        entries.retain(|entry| is_shown(entry, options));
+        sort_entries(&mut entries, options);
+        Ok(Listing { entries, errors })
+    }
+
+    // The entries one at a time, as read_dir yields them, without keeping
+    // them around: for huge directories, where we can't afford to collect
+    // everything before printing the first name. Only for listings that
+    // stream.
+    pub fn entries(&self, dir: &Path) -> io::Result<Entries<'_>> {
+        let options = &self.options;
+        Ok(Entries {
+            lister: self,
+            dir: dir.to_path_buf(),
+            read_dir: Some(fs::read_dir(dir)?),
+            // As in list, . and .. come first.
+            dots: if options.all { DOTS.iter() } else { [].iter() },
+            gitignore: options.gitignore.then(|| GitIgnore::for_directory(dir)),
+        })
+    }
+
+    // The directory and, with -R, everything below it, in the order ls
+    // prints them: each directory is followed by its entries, and then by
+    // its subdirectories.
+    pub fn walk(&self, dir: MyDirEntry) -> Walk<'_> {
+        Walk {
+            lister: self,
+            pending: vec![(dir, 0)],
+            ancestors: Vec::new(),
+            queued: VecDeque::new(),
+            streaming: None,
+        }
+    }
+
+    // The entries below the directory for --tree, depth first: each
+    // subdirectory is followed by its own entries, down to the depth given
+    // to --tree.
+    pub fn tree(&self, dir: MyDirEntry) -> TreeWalk<'_> {
+        TreeWalk {
+            lister: self,
+            levels: Vec::new(),
+            ancestors: Vec::new(),
+            queued: VecDeque::new(),
+            descend: Some(dir),
+        }
+    }
+
+    // Entries can be printed as they come only if they aren't sorted and
+    // nothing needs to be lined up across them: not the long format, not a
+    // grid, not the columns of -i, -s and -Z, not a tree.
+    pub fn streams(&self) -> bool {
+        let options = &self.options;
+        options.sort == SortBy::Unsorted && !options.group_directories_first &&
+            (options.json.is_some() || (!options.long && options.layout == Layout::OneLine)) &&
+            !(options.inode || options.blocks || options.context) && options.tree.is_none()
+    }
+
+    // A plain listing of names, sorted by name or not at all, needs
+    // nothing but read_dir; so do -R, --tree, --gitignore and
+    // --group-directories-first, which only need to know the directories
+    // (see needs_file_type). Everything else shows, sorts or filters by
+    // what is in the inode.
+    pub fn needs_metadata(&self) -> bool {
+        let options = &self.options;
+        options.long || options.json.is_some() ||
+            options.colors.as_ref().is_some_and(|colors| colors.needs_metadata()) ||
+            options.indicators != Indicators::None ||
+            options.inode || options.blocks || options.context || options.git ||
+            matches!(options.sort, SortBy::Time | SortBy::Size)
+    }
+
+    // Whether we keep the types that readdir gives us (d_type) for the
+    // entries we don't stat. On the file systems that don't fill in d_type,
+    // getting the type takes an lstat, which a plain listing can do without.
+    fn needs_file_type(&self) -> bool {
+        let options = &self.options;
+        options.colors.is_some() || options.gitignore || options.recursive ||
+            options.tree.is_some() || options.group_directories_first
+    }
+}
+
+const DOTS: &[&str] = &[".", ".."];
+
+#[derive(Debug)]
+pub struct Entries<'a> {
+    lister: &'a Lister,
+    dir: PathBuf,
+    // None once read_dir is done, or has failed.
+    read_dir: Option<fs::ReadDir>,
+    dots: std::slice::Iter<'static, &'static str>,
+    gitignore: Option<GitIgnore>,
+}
+
+impl Iterator for Entries<'_> {
+    type Item = Result<MyDirEntry, EntryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let options = &self.lister.options;
+        loop {
+            let entry = if let Some(name) = self.dots.next() {
+                let path = self.dir.join(name);
+                MyDirEntry::with_path(OsString::from(*name), path.clone(), options.dereference)
+                    .map_err(|error| EntryError::Access(path, error))
+            } else {
+                match self.read_dir.as_mut()?.next() {
+                    Some(Ok(dir_entry)) => {
+                        let path = dir_entry.path();
+                        if self.lister.needs_metadata() {
+                            MyDirEntry::new_from_path(path.clone(), options.dereference)
+                                .map_err(|error| EntryError::Access(path, error))
+                        } else {
+                            let file_type = self.lister.needs_file_type()
+                                .then(|| dir_entry.file_type().ok())
+                                .flatten();
+                            Ok(MyDirEntry::without_metadata(path, file_type, options.dereference))
+                        }
+                    }
+                    Some(Err(error)) => {
+                        self.read_dir = None;
+                        Err(EntryError::Reading(error))
+                    }
+                    None => {
+                        self.read_dir = None;
+                        return None;
+                    }
+                }
+            };
+            let mut entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => return Some(Err(error)),
+            };
+            if let Some(gitignore) = &self.gitignore {
+                if !entry.is_dot_or_dotdot() {
+                    entry.ignored = gitignore.is_ignored(&entry.file, entry.is_dir());
+                }
+            }
+            if is_shown(&entry, options) {
+                return Some(Ok(entry));
+            }
+        }
+    }
+}
+
+// What keeps a walk from listing a directory, or part of one.
+#[derive(Debug)]
+pub enum WalkError {
+    // We can't stat the directory.
+    Access(PathBuf, io::Error),
+    // We can't read the directory.
+    Open(PathBuf, io::Error),
+    // The directory is one of those we are in already, reached again
+    // through a symbolic link; listing it would never end.
+    Loop(PathBuf),
+    // A problem with one entry of the directory.
+    Entry(PathBuf, EntryError),
+}
+
+// The depth that comes with an error is the one of the directory it is
+// about: 0 for the directory the walk started from.
+#[derive(Debug)]
+pub enum WalkItem {
+    // A directory, whose entries come next.
+    Directory(Box<MyDirEntry>, usize),
+    // The entries of the last directory: all of them at once, sorted, or
+    // one at a time if the lister streams.
+    Entries(Vec<MyDirEntry>),
+    Error(WalkError, usize),
+}
+
+// The (device, inode) pairs in ancestors belong to the directories on the
+// way down to the one we are listing, one for each depth.
+#[derive(Debug)]
+pub struct Walk<'a> {
+    lister: &'a Lister,
+    // The directories still to list, the next one last.
+    pending: Vec<(MyDirEntry, usize)>,
+    ancestors: Vec<(u64, u64)>,
+    queued: VecDeque<WalkItem>,
+    streaming: Option<Streaming<'a>>,
+}
+
+// A directory that we read a little at a time, and the subdirectories
+// that we have found in it so far.
+#[derive(Debug)]
+struct Streaming<'a> {
+    dir: PathBuf,
+    depth: usize,
+    entries: Entries<'a>,
+    subdirectories: Vec<MyDirEntry>,
+}
+
+impl Iterator for Walk<'_> {
+    type Item = WalkItem;
+
+    fn next(&mut self) -> Option<WalkItem> {
+        loop {
+            if let Some(item) = self.queued.pop_front() {
+                return Some(item);
+            }
+            if let Some(streaming) = &mut self.streaming {
+                match streaming.entries.next() {
+                    Some(Ok(entry)) => {
+                        if self.lister.options.recursive && is_subdirectory(&entry) {
+                            streaming.subdirectories.push(entry.clone());
+                        }
+                        return Some(WalkItem::Entries(vec![entry]));
+                    }
+                    Some(Err(error)) => {
+                        let error = WalkError::Entry(streaming.dir.clone(), error);
+                        return Some(WalkItem::Error(error, streaming.depth));
+                    }
+                    None => {
+                        let depth = streaming.depth + 1;
+                        let subdirectories = std::mem::take(&mut streaming.subdirectories);
+                        self.pending.extend(subdirectories.into_iter().rev().map(|dir| (dir, depth)));
+                        self.streaming = None;
+                    }
+                }
+                continue;
+            }
+            let (dir, depth) = self.pending.pop()?;
+            self.ancestors.truncate(depth);
+            let dev_ino = match dir.dev_ino() {
+                Ok(dev_ino) => dev_ino,
+                Err(error) => return Some(WalkItem::Error(WalkError::Access(dir.path, error), depth)),
+            };
+            if self.ancestors.contains(&dev_ino) {
+                return Some(WalkItem::Error(WalkError::Loop(dir.path), depth));
+            }
+            self.ancestors.push(dev_ino);
+            self.read(&dir.path, depth);
+            return Some(WalkItem::Directory(Box::new(dir), depth));
+        }
+    }
+}
+
+impl<'a> Walk<'a> {
+    // Queues what comes after the directory's name: its entries, or the
+    // first of them if we stream.
+    fn read(&mut self, dir: &Path, depth: usize) {
+        let lister = self.lister;
+        if lister.streams() {
+            match lister.entries(dir) {
+                Ok(entries) => {
+                    self.streaming = Some(Streaming {
+                        dir: dir.to_path_buf(), depth, entries, subdirectories: Vec::new(),
+                    });
+                }
+                Err(error) => {
+                    self.queued.push_back(WalkItem::Error(WalkError::Open(dir.to_path_buf(), error), depth));
+                }
+            }
+            return;
+        }
+        match lister.list(dir) {
+            Ok(listing) => {
+                for error in listing.errors {
+                    self.queued.push_back(WalkItem::Error(WalkError::Entry(dir.to_path_buf(), error), depth));
+                }
+                if lister.options.recursive {
+                    let subdirectories = listing.entries.iter().rev()
+                        .filter(|entry| is_subdirectory(entry))
+                        .map(|entry| (entry.clone(), depth + 1));
+                    self.pending.extend(subdirectories);
+                }
+                self.queued.push_back(WalkItem::Entries(listing.entries));
+            }
+            Err(error) => {
+                self.queued.push_back(WalkItem::Error(WalkError::Open(dir.to_path_buf(), error), depth));
+            }
+        }
+    }
+}
+
+fn is_subdirectory(entry: &MyDirEntry) -> bool {
+    entry.is_dir() && !entry.is_dot_or_dotdot()
+}
+
+#[derive(Debug)]
+pub enum TreeItem {
+    // An entry, and for each level from the top down to the entry, whether
+    // the entry there is the last one of its directory: what it takes to
+    // draw the lines in front of the name.
+    Entry(Box<MyDirEntry>, Vec<bool>),
+    Error(WalkError, usize),
+}
+
+// As in Walk, ancestors has the (device, inode) pair of each directory on
+// the way down.
+#[derive(Debug)]
+pub struct TreeWalk<'a> {
+    lister: &'a Lister,
+    // For each directory on the way down, the entries still to come.
+    levels: Vec<std::vec::IntoIter<MyDirEntry>>,
+    ancestors: Vec<(u64, u64)>,
+    queued: VecDeque<TreeItem>,
+    // The directory whose entries come next, one level further down.
+    descend: Option<MyDirEntry>,
+}
+
+impl Iterator for TreeWalk<'_> {
+    type Item = TreeItem;
+
+    fn next(&mut self) -> Option<TreeItem> {
+        loop {
+            if let Some(item) = self.queued.pop_front() {
+                return Some(item);
+            }
+            if let Some(dir) = self.descend.take() {
+                self.read(dir);
+                continue;
+            }
+            let level = self.levels.last_mut()?;
+            let Some(entry) = level.next() else {
+                self.levels.pop();
+                continue;
+            };
+            let lasts = self.levels.iter().map(|level| level.len() == 0).collect();
+            if entry.is_dir() && self.levels.len() < self.lister.options.tree.unwrap_or(0) {
+                self.descend = Some(entry.clone());
+            }
+            return Some(TreeItem::Entry(Box::new(entry), lasts));
+        }
+    }
+}
+
+impl TreeWalk<'_> {
+    fn read(&mut self, dir: MyDirEntry) {
+        let depth = self.levels.len();
+        self.ancestors.truncate(depth);
+        let dev_ino = match dir.dev_ino() {
+            Ok(dev_ino) => dev_ino,
+            Err(error) => {
+                self.queued.push_back(TreeItem::Error(WalkError::Access(dir.path, error), depth));
+                return;
+            }
+        };
+        if self.ancestors.contains(&dev_ino) {
+            self.queued.push_back(TreeItem::Error(WalkError::Loop(dir.path), depth));
+            return;
+        }
+        self.ancestors.push(dev_ino);
+        match self.lister.list(&dir.path) {
+            Ok(mut listing) => {
+                for error in listing.errors {
+                    let error = WalkError::Entry(dir.path.clone(), error);
+                    self.queued.push_back(TreeItem::Error(error, depth));
+                }
+                listing.entries.retain(|entry| !entry.is_dot_or_dotdot());
+                self.levels.push(listing.entries.into_iter());
+            }
+            Err(error) => self.queued.push_back(TreeItem::Error(WalkError::Open(dir.path, error), depth)),
+        }
+    }
+}
+
+// On a network file system every stat is a round trip to the server, so
+// for large directories we keep a few of them in flight at once. The
+// threads take the entries in batches, and the results keep the order of
+// the paths.
+const STAT_THREADS: usize = 8;
+const STAT_BATCH: usize = 64;
+
+fn stat_entries(paths: &[PathBuf], dereference: bool) ->
+    Vec<(&PathBuf, io::Result<MyDirEntry>)>
+{
+    let threads = paths.len().div_ceil(STAT_BATCH).min(STAT_THREADS);
+    if threads <= 1 {
+        return paths.iter()
+            .map(|path| (path, MyDirEntry::new_from_path(path.clone(), dereference)))
+            .collect();
+    }
+    let results: Vec<OnceLock<io::Result<MyDirEntry>>> = paths.iter().map(|_| OnceLock::new()).collect();
+    let next = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let start = next.fetch_add(STAT_BATCH, atomic::Ordering::Relaxed);
+                if start >= paths.len() {
+                    break;
+                }
+                for index in start..paths.len().min(start + STAT_BATCH) {
+                    let entry = MyDirEntry::new_from_path(paths[index].clone(), dereference);
+                    // Each index is handed out once, so the cell is empty.
+                    let _ = results[index].set(entry);
+                }
+            });
+        }
+    });
+    paths.iter()
+        .zip(results)
+        .map(|(path, result)| (path, result.into_inner().expect("every entry has been read")))
+        .collect()
+}
+
+fn sort_entries(entries: &mut [MyDirEntry], options: &LsOptions) {
+    if options.sort != SortBy::Unsorted {
+        entries.sort_by(|a, b| {
+            let ordering = compare_entries(a, b, options);
+            if options.reverse {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+    }
+    // A stable sort keeps the order we have just established within the
+    // directories and within the files.
+    if options.group_directories_first {
+        entries.sort_by_key(|entry| !entry.is_dir_or_link_to_dir());
+    }
+}
+
+// Entries that compare equal by time, size or extension are ordered by
+// name, so that the output doesn't depend on the order of read_dir.
+fn compare_entries(a: &MyDirEntry, b: &MyDirEntry, options: &LsOptions) -> Ordering {
+    let a_file = a.file.to_string_lossy();
+    let b_file = b.file.to_string_lossy();
+    let by_name = || a_file.to_lowercase().cmp(&b_file.to_lowercase());
+    match options.sort {
+        SortBy::Name | SortBy::Unsorted => by_name(),
+        SortBy::Time => {
+            let a_time = a.metadata().ok().and_then(|m| entry_time(m, options.time_field));
+            let b_time = b.metadata().ok().and_then(|m| entry_time(m, options.time_field));
+            b_time.cmp(&a_time).then_with(by_name)
+        }
+        SortBy::Size => {
+            let a_size = a.metadata().ok().map(|m| m.len());
+            let b_size = b.metadata().ok().map(|m| m.len());
+            b_size.cmp(&a_size).then_with(by_name)
+        }
+        SortBy::Extension => extension(&a_file).cmp(extension(&b_file)).then_with(by_name),
+        SortBy::Version => compare_versions(&a_file, &b_file),
+    }
+}
+
+// The extension is whatever follows the last dot; a file without a dot
+// (or a dotfile like .bashrc) has an empty extension and sorts first.
+fn extension(file: &str) -> &str {
+    match file.rfind('.') {
+        Some(index) if index > 0 => &file[index + 1..],
+        _ => "",
+    }
+}
+
+// Natural sort: runs of digits compare as numbers, so that file-9 comes
+// before file-10 and 1.2.9 before 1.2.10.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let mut a_rest = a;
+    let mut b_rest = b;
+    loop {
+        match (a_rest.is_empty(), b_rest.is_empty()) {
+            (true, true) => return a.cmp(b),
+            (true, false) => return Ordering::Less,
+            (false, true) => return Ordering::Greater,
+            _ => {}
+        }
+        let a_digits = a_rest.starts_with(|c: char| c.is_ascii_digit());
+        let b_digits = b_rest.starts_with(|c: char| c.is_ascii_digit());
+        let (a_part, a_next) = split_run(a_rest, a_digits);
+        let (b_part, b_next) = split_run(b_rest, b_digits);
+        let ordering = if a_digits && b_digits {
+            let a_number = a_part.trim_start_matches('0');
+            let b_number = b_part.trim_start_matches('0');
+            a_number.len().cmp(&b_number.len()).then_with(|| a_number.cmp(b_number))
+        } else {
+            a_part.cmp(b_part)
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+        a_rest = a_next;
+        b_rest = b_next;
+    }
+}
+
+// Splits off the leading run of digits (or of non-digits).
+fn split_run(s: &str, digits: bool) -> (&str, &str) {
+    let end = s.find(|c: char| c.is_ascii_digit() != digits).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+// If file name begins with '.' and neither -a nor -A was used in the
+// command, we skip the file. The same goes for the names matching the
+// --hide patterns, while the --ignore patterns hide a name no matter what.
+// Files that git ignores are only shown with -a.
+fn is_shown(entry: &MyDirEntry, options: &LsOptions) -> bool {
+    if options.ignore.iter().any(|pattern| matches_pattern(pattern, &entry.file)) {
+        return false;
+    }
+    if entry.ignored && !options.all {
+        return false;
+    }
+    if options.all || options.almost_all {
+        return true;
+    }
+    !entry.file.as_encoded_bytes().starts_with(b".") &&
+        !options.hide.iter().any(|pattern| matches_pattern(pattern, &entry.file))
+}
+
+// The libc crate doesn't declare fnmatch(3), so we do it ourselves.
+extern "C" {
+    fn fnmatch(pattern: *const libc::c_char, string: *const libc::c_char, flags: libc::c_int)
+        -> libc::c_int;
+}
+
+const FNM_PERIOD: libc::c_int = 1 << 2;
+
+// Shell pattern matching, as done by fnmatch(3). With FNM_PERIOD a leading
+// dot has to be matched explicitly, so that '*' doesn't match ".bashrc".
+fn matches_pattern(pattern: &CString, file: &OsStr) -> bool {
+    let Ok(file) = CString::new(file.as_encoded_bytes()) else {
+        return false;
+    };
+    unsafe { fnmatch(pattern.as_ptr(), file.as_ptr(), FNM_PERIOD) == 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_style_parses_the_gnu_styles() {
+        let style = TimeStyle::parse("long-iso").unwrap();
+        assert_eq!(style.recent, "%Y-%m-%d %H:%M");
+        assert_eq!(style.old, "%Y-%m-%d %H:%M");
+        let style = TimeStyle::parse("iso").unwrap();
+        assert_eq!(style.recent, "%m-%d %H:%M");
+        assert_eq!(style.old, "%Y-%m-%d ");
+        assert!(TimeStyle::parse("posix-iso").is_err());
+    }
+
+    #[test]
+    fn time_style_format_puts_the_old_format_first() {
+        let style = TimeStyle::parse("+%Y-%m-%d\n%m-%d %H:%M").unwrap();
+        assert_eq!(style.old, "%Y-%m-%d");
+        assert_eq!(style.recent, "%m-%d %H:%M");
+        let style = TimeStyle::parse("+%H:%M").unwrap();
+        assert_eq!(style.old, "%H:%M");
+        assert_eq!(style.recent, "%H:%M");
+    }
+
+    #[test]
+    fn time_style_rejects_broken_formats() {
+        assert!(TimeStyle::parse("+%").is_err());
+        assert!(TimeStyle::parse("%Y").is_err());
+    }
+
+    fn blocks(arg: &str) -> (u64, String) {
+        match SizeStyle::parse_block_size(arg) {
+            Ok(SizeStyle::Blocks(size, suffix)) => (size, suffix),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn block_size_units() {
+        assert_eq!(blocks("512"), (512, String::new()));
+        assert_eq!(blocks("K"), (1024, "K".to_owned()));
+        assert_eq!(blocks("MiB"), (1024 * 1024, "MiB".to_owned()));
+        assert_eq!(blocks("KB"), (1000, "kB".to_owned()));
+        assert_eq!(blocks("GB"), (1000 * 1000 * 1000, "GB".to_owned()));
+    }
+
+    #[test]
+    fn block_size_with_a_number_has_no_suffix() {
+        assert_eq!(blocks("1M"), (1024 * 1024, String::new()));
+        assert_eq!(blocks("4k"), (4096, String::new()));
+    }
+
+    #[test]
+    fn block_size_rejects_nonsense() {
+        assert!(SizeStyle::parse_block_size("0").is_err());
+        assert!(SizeStyle::parse_block_size("X").is_err());
+        assert!(SizeStyle::parse_block_size("Kb").is_err());
+        assert!(SizeStyle::parse_block_size("99999999999999999999E").is_err());
+    }
+
+    #[test]
+    fn versions_compare_numbers_by_value() {
+        assert_eq!(compare_versions("file2", "file10"), Ordering::Less);
+        assert_eq!(compare_versions("1.9.1", "1.10"), Ordering::Less);
+        assert_eq!(compare_versions("a10b", "a9c"), Ordering::Greater);
+        assert_eq!(compare_versions("file", "file1"), Ordering::Less);
+    }
+
+    #[test]
+    fn versions_with_leading_zeros_fall_back_to_the_names() {
+        assert_eq!(compare_versions("v007", "v7"), Ordering::Less);
+        assert_eq!(compare_versions("v7", "v7"), Ordering::Equal);
+    }
+
+    #[test]
+    fn walks_list_each_directory_before_its_subdirectories() {
+        let top = std::env::temp_dir().join(format!("ls-rs-walk-{}", std::process::id()));
+        fs::create_dir_all(top.join("a/b")).unwrap();
+        fs::create_dir_all(top.join("c")).unwrap();
+        fs::write(top.join("a/file"), "").unwrap();
+        std::os::unix::fs::symlink("..", top.join("a/b/up")).unwrap();
+        let lister = Lister::new(LsOptions { recursive: true, dereference: true, ..LsOptions::default() });
+        let items: Vec<String> = lister.walk(MyDirEntry::new(top.clone().into(), false).unwrap())
+            .map(|item| match item {
+                WalkItem::Directory(dir, depth) => format!("{} {}", depth, dir.file.to_string_lossy()),
+                WalkItem::Entries(entries) => format!("{} entries", entries.len()),
+                WalkItem::Error(error, depth) => format!("{} {:?}", depth, error),
+            })
+            .collect();
+        let lister = Lister::new(LsOptions { tree: Some(2), ..LsOptions::default() });
+        let tree: Vec<(String, Vec<bool>)> = lister.tree(MyDirEntry::new(top.clone().into(), false).unwrap())
+            .filter_map(|item| match item {
+                TreeItem::Entry(entry, lasts) => Some((entry.file.to_string_lossy().into_owned(), lasts)),
+                TreeItem::Error(..) => None,
+            })
+            .collect();
+        fs::remove_dir_all(&top).unwrap();
+        let top_name = top.to_string_lossy();
+        assert_eq!(items[..5], [format!("0 {}", top_name), "2 entries".to_owned(),
+            "1 a".to_owned(), "2 entries".to_owned(), "2 b".to_owned()]);
+        assert!(items[6].starts_with("3 Loop("), "{:?}", items);
+        assert_eq!(items[7..], ["1 c".to_owned(), "0 entries".to_owned()]);
+        let names: Vec<(&str, &[bool])> = tree.iter().map(|(name, lasts)| (name.as_str(), &lasts[..])).collect();
+        assert_eq!(names, [("a", &[false][..]), ("b", &[false, false]), ("file", &[false, true]),
+            ("c", &[true])]);
+    }
+
+    #[test]
+    fn the_type_from_readdir_saves_a_stat() {
+        let file_type = fs::symlink_metadata("/").unwrap().file_type();
+        let path = PathBuf::from("/nonexistent/ls-rs");
+        let entry = MyDirEntry::without_metadata(path.clone(), Some(file_type), false);
+        assert!(entry.is_dir() && !entry.is_symlink());
+        assert_eq!(entry.target(), None);
+        assert!(entry.metadata().is_err());
+        // With -L the type of a link tells us nothing.
+        let file_type = fs::symlink_metadata("/proc/self").unwrap().file_type();
+        let entry = MyDirEntry::without_metadata(path, Some(file_type), true);
+        assert!(entry.file_type().is_err());
+    }
+
+    #[test]
+    fn a_failed_late_stat_is_an_error_and_not_a_panic() {
+        let entry = MyDirEntry::without_metadata(PathBuf::from("/nonexistent/ls-rs"), None, false);
+        assert!(entry.metadata().is_err());
+        assert!(entry.dev_ino().is_err());
+        assert_eq!(entry.target(), None);
+        assert!(!entry.is_dir_or_link_to_dir());
+        assert!(!entry.is_broken_link());
+    }
+}
@@ -1,57 +1,63 @@
-use clap::{arg, command, Arg, ArgAction};
-use std::fs;
-use std::io::Error;
-use std::fs::Metadata;
-use std::os::linux::fs::MetadataExt;
-use std::os::unix::fs::PermissionsExt;
-use libc;
-use chrono::Local;
-use systime_converter;
-use colored::Colorize;
-use std::path::PathBuf;
-
-#[derive(Debug)]
-struct LsOptions {
-    long: bool,
-    all: bool,
-}
+use clap::{arg, command, value_parser, Arg, ArgAction};
+use clap::builder::PossibleValue;
+use std::sync::atomic::{self, AtomicI32};
+use std::ffi::{CString, OsString};
+use std::io::{self, Write};
+use std::path::Path;
 
-#[derive(Debug)]
-struct MyDirEntry {
-    file: String,
-    path: PathBuf,
-    metadata: Metadata,
-}
+use ls_rs::colors::Colors;
+use ls_rs::quoting::{self, QuotingStyle};
+use ls_rs::{EntryError, Indicators, JsonFormat, Layout, Lister, LsOptions, MyDirEntry, Renderer};
+use ls_rs::{SizeStyle, SortBy, TimeField, TimeStyle, TreeItem, WalkError, WalkItem};
 
-impl MyDirEntry {
-    fn new(file: String) -> Option<MyDirEntry> {
-        let path = PathBuf::from(&file);
-        let metadata = match fs::metadata(file.clone()) {
-            Ok(metadata) => metadata,
-            _ => return None,
-        };
-        Some(MyDirEntry { file, path, metadata })
-    }
+// Exit status, as in GNU ls: 0 if everything went fine, 1 for minor
+// problems (for example, a subdirectory we cannot read), 2 for serious
+// trouble (for example, a file on the command line that doesn't exist).
+const EXIT_MINOR: i32 = 1;
+const EXIT_SERIOUS: i32 = 2;
 
-    fn new_from_path(path: PathBuf) -> Option<MyDirEntry> {
-        let file = path.into_os_string();
-        if let Ok(file) = file.into_string() {
-            return MyDirEntry::new(file);
-        }
-        None
+static EXIT_STATUS: AtomicI32 = AtomicI32::new(0);
+
+fn main() {
+    // Rust ignores SIGPIPE, which would turn `ls-rs | head` into a panic
+    // on the first write after head exits. Like any other Unix tool, we
+    // just want to be terminated quietly.
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
     }
+    // All that can go wrong in run itself is writing the output.
+    if let Err(error) = run() {
+        eprintln!("ls: write error: {}", describe_error(&error));
+        set_exit_status(EXIT_SERIOUS);
+    }
+    std::process::exit(EXIT_STATUS.load(atomic::Ordering::Relaxed));
 }
 
-fn main() {
-    match run() {
-        Ok(_) => return,
-        Err(e) => {
-            eprintln!("Error: {}", e);
+// The exit status only ever goes up: one serious problem is enough to
+// exit with 2, no matter how many minor ones follow.
+fn set_exit_status(status: i32) {
+    EXIT_STATUS.fetch_max(status, atomic::Ordering::Relaxed);
+}
+
+// Prints the message in the format of GNU ls, for example
+// "ls: cannot access 'x': No such file or directory".
+fn report_error(message: &str, path: &Path, error: &io::Error, status: i32) {
+    eprintln!("ls: {} {}: {}", message, quoting::quote_always(path.as_os_str()), describe_error(error));
+    set_exit_status(status);
+}
+
+// io::Error's Display appends " (os error 2)", which ls doesn't.
+fn describe_error(error: &io::Error) -> String {
+    match error.raw_os_error() {
+        Some(code) => {
+            let message = unsafe { std::ffi::CStr::from_ptr(libc::strerror(code)) };
+            message.to_string_lossy().into_owned()
         }
+        None => error.to_string(),
     }
 }
 
-fn run() -> Result<(), Box<dyn std::error::Error>> {
+fn run() -> io::Result<()> {
     let matches = command!("ls") // requires `cargo` feature
         .version("0.1")
         .author("Aleksandar J. <ajanicij@yahoo.com>")
@@ -60,171 +66,728 @@ fn run() -> Result<(), Box<dyn std::error::Error>> {
             Arg::new("long")
                 .short('l')
                 .action(ArgAction::SetTrue)
+                .overrides_with_all(["one", "across", "columns", "format"])
                 .help("use a long listing format"),
         )
+        .arg(
+            Arg::new("one")
+                .short('1')
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["long", "across", "columns", "format"])
+                .help("list one file per line")
+        )
+        .arg(
+            Arg::new("columns")
+                .short('C')
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["long", "one", "across", "format"])
+                .help("list entries by columns")
+        )
+        .arg(
+            Arg::new("across")
+                .short('x')
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["long", "one", "columns", "format"])
+                .help("list entries by lines instead of by columns")
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_name("WORD")
+                .value_parser([
+                    "across", "horizontal", "long", "verbose", "single-column", "vertical",
+                    "json", "ndjson",
+                ])
+                .overrides_with_all(["long", "one", "across", "columns"])
+                .help("across -x, long -l, single-column -1, verbose -l, vertical -C, \
+                    json or ndjson for one JSON object per entry")
+        )
+        .arg(
+            Arg::new("zero")
+                .long("zero")
+                .action(ArgAction::SetTrue)
+                .help("end each output line with NUL, not newline")
+        )
+        .arg(
+            Arg::new("dired")
+                .short('D')
+                .long("dired")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("zero")
+                .help("generate output designed for Emacs' dired mode")
+        )
         .arg(
             Arg::new("all")
                 .short('a')
                 .long("all")
                 .action(ArgAction::SetTrue)
+                .overrides_with("almost-all")
                 .help("do not ignore entries starting with .")
         )
+        .arg(
+            Arg::new("almost-all")
+                .short('A')
+                .long("almost-all")
+                .action(ArgAction::SetTrue)
+                .overrides_with("all")
+                .help("do not list implied . and ..")
+        )
+        .arg(
+            Arg::new("ignore-backups")
+                .short('B')
+                .long("ignore-backups")
+                .action(ArgAction::SetTrue)
+                .help("do not list implied entries ending with ~")
+        )
+        .arg(
+            Arg::new("ignore")
+                .short('I')
+                .long("ignore")
+                .value_name("PATTERN")
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(OsString))
+                .help("do not list implied entries matching shell PATTERN")
+        )
+        .arg(
+            Arg::new("hide")
+                .long("hide")
+                .value_name("PATTERN")
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(OsString))
+                .help("do not list implied entries matching shell PATTERN (overridden by -a or -A)")
+        )
+        .arg(
+            Arg::new("gitignore")
+                .long("gitignore")
+                .action(ArgAction::SetTrue)
+                .help("do not list entries ignored by git (with -a, mark them in the long format)")
+        )
+        .arg(
+            Arg::new("git")
+                .long("git")
+                .action(ArgAction::SetTrue)
+                .help("with -l, show the git status of each file")
+        )
+        .arg(
+            Arg::new("recursive")
+                .short('R')
+                .long("recursive")
+                .action(ArgAction::SetTrue)
+                .help("list subdirectories recursively")
+        )
+        .arg(
+            Arg::new("tree")
+                .long("tree")
+                .value_name("DEPTH")
+                .num_args(0..=1)
+                .require_equals(true)
+                .value_parser(value_parser!(u64).range(1..))
+                .conflicts_with("format")
+                .help("list subdirectories recursively as a tree, at most DEPTH levels deep")
+        )
+        .arg(
+            Arg::new("dereference")
+                .short('L')
+                .long("dereference")
+                .action(ArgAction::SetTrue)
+                .help("when showing file information for a symbolic link, show information for the file the link references rather than for the link itself")
+        )
+        .arg(
+            Arg::new("inode")
+                .short('i')
+                .long("inode")
+                .action(ArgAction::SetTrue)
+                .help("print the index number of each file")
+        )
+        .arg(
+            Arg::new("size")
+                .short('s')
+                .long("size")
+                .action(ArgAction::SetTrue)
+                .help("print the allocated size of each file, in blocks")
+        )
+        .arg(
+            Arg::new("context")
+                .short('Z')
+                .long("context")
+                .action(ArgAction::SetTrue)
+                .help("print any security context of each file")
+        )
+        .arg(
+            Arg::new("numeric-uid-gid")
+                .short('n')
+                .long("numeric-uid-gid")
+                .action(ArgAction::SetTrue)
+                .help("like -l, but list numeric user and group IDs")
+        )
+        .arg(
+            Arg::new("sort-time")
+                .short('t')
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["sort-size", "sort-extension", "sort-version", "unsorted"])
+                .help("sort by time, newest first")
+        )
+        .arg(
+            Arg::new("sort-size")
+                .short('S')
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["sort-time", "sort-extension", "sort-version", "unsorted"])
+                .help("sort by file size, largest first")
+        )
+        .arg(
+            Arg::new("sort-extension")
+                .short('X')
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["sort-time", "sort-size", "sort-version", "unsorted"])
+                .help("sort alphabetically by entry extension")
+        )
+        .arg(
+            Arg::new("sort-version")
+                .short('v')
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["sort-time", "sort-size", "sort-extension", "unsorted"])
+                .help("natural sort of (version) numbers within text")
+        )
+        .arg(
+            Arg::new("unsorted")
+                .short('U')
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["sort-time", "sort-size", "sort-extension", "sort-version"])
+                .help("do not sort; list entries in directory order")
+        )
+        .arg(
+            Arg::new("all-unsorted")
+                .short('f')
+                .action(ArgAction::SetTrue)
+                .help("list all entries in directory order; same as -a -U")
+        )
+        .arg(
+            Arg::new("reverse")
+                .short('r')
+                .long("reverse")
+                .action(ArgAction::SetTrue)
+                .help("reverse order while sorting")
+        )
+        .arg(
+            Arg::new("group-directories-first")
+                .long("group-directories-first")
+                .action(ArgAction::SetTrue)
+                .help("group directories before files")
+        )
+        .arg(
+            Arg::new("human-readable")
+                .short('h')
+                .long("human-readable")
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["si", "block-size"])
+                .help("with -l, print sizes like 1K 234M 2G etc.")
+        )
+        .arg(
+            Arg::new("si")
+                .long("si")
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["human-readable", "block-size"])
+                .help("likewise, but use powers of 1000 not 1024")
+        )
+        .arg(
+            Arg::new("block-size")
+                .long("block-size")
+                .value_name("SIZE")
+                .value_parser(SizeStyle::parse_block_size)
+                .overrides_with_all(["human-readable", "si"])
+                .help("with -l, scale sizes by SIZE when printing them; e.g., '--block-size=M'")
+        )
+        .arg(
+            Arg::new("color")
+                .long("color")
+                .value_name("WHEN")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("always")
+                .value_parser([
+                    PossibleValue::new("always").alias("yes").alias("force"),
+                    PossibleValue::new("auto").alias("tty").alias("if-tty"),
+                    PossibleValue::new("never").alias("no").alias("none"),
+                ])
+                .help("color the output WHEN; more info below")
+        )
+        .arg(
+            Arg::new("hyperlink")
+                .long("hyperlink")
+                .value_name("WHEN")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("always")
+                .value_parser([
+                    PossibleValue::new("always").alias("yes").alias("force"),
+                    PossibleValue::new("auto").alias("tty").alias("if-tty"),
+                    PossibleValue::new("never").alias("no").alias("none"),
+                ])
+                .help("hyperlink file names WHEN")
+        )
+        .after_help("Without --color, or with --color=auto, ls-rs emits color codes only when \
+            standard output is connected to a terminal. The LS_COLORS environment variable \
+            can change the settings. Use the dircolors command to set it.")
+        .arg(
+            Arg::new("directory")
+                .short('d')
+                .long("directory")
+                .action(ArgAction::SetTrue)
+                .help("list directories themselves, not their contents")
+        )
+        .arg(
+            Arg::new("classify")
+                .short('F')
+                .long("classify")
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["file-type", "indicator-slash"])
+                .help("append indicator (one of */=@|) to entries")
+        )
+        .arg(
+            Arg::new("file-type")
+                .long("file-type")
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["classify", "indicator-slash"])
+                .help("likewise, except do not append '*'")
+        )
+        .arg(
+            Arg::new("indicator-slash")
+                .short('p')
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["classify", "file-type"])
+                .help("append / indicator to directories")
+        )
+        .arg(
+            Arg::new("quoting-style")
+                .long("quoting-style")
+                .value_name("WORD")
+                .value_parser(["literal", "shell", "shell-escape", "c", "escape"])
+                .overrides_with_all(["escape", "literal"])
+                .help("use quoting style WORD for entry names: literal, shell, shell-escape, c, escape")
+        )
+        .arg(
+            Arg::new("escape")
+                .short('b')
+                .long("escape")
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["quoting-style", "literal"])
+                .help("print C-style escapes for nongraphic characters")
+        )
+        .arg(
+            Arg::new("literal")
+                .short('N')
+                .long("literal")
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["quoting-style", "escape"])
+                .help("print entry names without quoting")
+        )
+        .arg(
+            Arg::new("hide-control-chars")
+                .short('q')
+                .long("hide-control-chars")
+                .action(ArgAction::SetTrue)
+                .overrides_with("show-control-chars")
+                .help("print ? instead of nongraphic characters")
+        )
+        .arg(
+            Arg::new("show-control-chars")
+                .long("show-control-chars")
+                .action(ArgAction::SetTrue)
+                .overrides_with("hide-control-chars")
+                .help("show nongraphic characters as-is (the default, unless program is 'ls' and output is a terminal)")
+        )
+        .arg(
+            Arg::new("time")
+                .long("time")
+                .value_name("WORD")
+                .value_parser([
+                    PossibleValue::new("mtime").alias("modification"),
+                    PossibleValue::new("atime").alias("access").alias("use"),
+                    PossibleValue::new("ctime").alias("status"),
+                    PossibleValue::new("birth").alias("creation"),
+                ])
+                .overrides_with_all(["atime", "ctime"])
+                .help("select which timestamp is used to display or sort")
+        )
+        .arg(
+            Arg::new("atime")
+                .short('u')
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["time", "ctime"])
+                .help("with -lt: sort by, and show, access time; with -l: show access time and sort by name; otherwise: sort by access time, newest first")
+        )
+        .arg(
+            Arg::new("ctime")
+                .short('c')
+                .action(ArgAction::SetTrue)
+                .overrides_with_all(["time", "atime"])
+                .help("with -lt: sort by, and show, ctime; with -l: show ctime and sort by name; otherwise: sort by ctime, newest first")
+        )
+        .arg(
+            Arg::new("time-style")
+                .long("time-style")
+                .value_name("TIME_STYLE")
+                .value_parser(TimeStyle::parse)
+                .help("time/date format with -l: full-iso, long-iso, iso, locale, or +FORMAT")
+        )
         .arg(
             arg!([FILE])
+                .value_parser(value_parser!(OsString))
                 .action(ArgAction::Append),
         )
+        .disable_help_flag(true)
+        .arg(
+            Arg::new("help")
+                .long("help")
+                .action(ArgAction::Help)
+                .help("display this help and exit")
+        )
         .get_matches();
 
-    let options = LsOptions {
-        long: matches.get_flag("long"),
-        all: matches.get_flag("all"),
+    // Without -1, -C or -x we print a grid on a terminal, but one name per
+    // line when the output goes to a pipe or a file. --zero is for other
+    // programs to read: one name per line, no colors, no quoting, unless
+    // asked for.
+    let zero = matches.get_flag("zero");
+    let format = matches.get_one::<String>("format").map(|format| format.as_str());
+    let layout = if matches.get_flag("one") || format == Some("single-column") {
+        Layout::OneLine
+    } else if matches.get_flag("across") || matches!(format, Some("across" | "horizontal")) {
+        Layout::Across
+    } else if matches.get_flag("columns") || format == Some("vertical") ||
+        (!zero && is_stdout_tty())
+    {
+        Layout::Columns
+    } else {
+        Layout::OneLine
     };
-    
-    if options.long {
-        println!("Printing long information for each file...");
-    }
-    if options.all {
-        println!("Including files whose name starts with '.' in the list...");
+
+    let time_field = if matches.get_flag("atime") {
+        TimeField::Access
+    } else if matches.get_flag("ctime") {
+        TimeField::Change
+    } else {
+        match matches.get_one::<String>("time").map(|time| time.as_str()) {
+            Some("atime" | "access" | "use") => TimeField::Access,
+            Some("ctime" | "status") => TimeField::Change,
+            Some("birth" | "creation") => TimeField::Birth,
+            _ => TimeField::Modification,
+        }
+    };
+    let time_style = match matches.get_one::<TimeStyle>("time-style") {
+        Some(style) => style.clone(),
+        None => std::env::var("TIME_STYLE").ok()
+            .and_then(|style| TimeStyle::parse(&style).ok())
+            .unwrap_or_default(),
+    };
+    let long = matches.get_flag("long") || matches.get_flag("numeric-uid-gid") ||
+        matches!(format, Some("long" | "verbose"));
+    let json = match format {
+        Some("json") => Some(JsonFormat::Array),
+        Some("ndjson") => Some(JsonFormat::Lines),
+        _ => None,
+    };
+    // Like GNU ls, --dired does nothing outside the long format.
+    let dired = matches.get_flag("dired") && long && json.is_none() && !matches.contains_id("tree");
+
+    let sort = if matches.get_flag("sort-time") {
+        SortBy::Time
+    } else if matches.get_flag("sort-size") {
+        SortBy::Size
+    } else if matches.get_flag("sort-extension") {
+        SortBy::Extension
+    } else if matches.get_flag("sort-version") {
+        SortBy::Version
+    } else if matches.get_flag("unsorted") || matches.get_flag("all-unsorted") {
+        SortBy::Unsorted
+    } else if !long && time_field != TimeField::Modification {
+        // Like GNU ls, -u, -c and --time without -l sort by that time.
+        SortBy::Time
+    } else {
+        SortBy::Name
+    };
+
+    let size_style = if matches.get_flag("human-readable") {
+        SizeStyle::Human(1024)
+    } else if matches.get_flag("si") {
+        SizeStyle::Human(1000)
+    } else if let Some(style) = matches.get_one::<SizeStyle>("block-size") {
+        style.clone()
+    } else {
+        SizeStyle::Default
+    };
+
+    let indicators = if matches.get_flag("classify") {
+        Indicators::Classify
+    } else if matches.get_flag("file-type") {
+        Indicators::FileType
+    } else if matches.get_flag("indicator-slash") {
+        Indicators::Slash
+    } else {
+        Indicators::None
+    };
+
+    // Like GNU ls: shell-escape on a terminal, literal otherwise, unless
+    // the QUOTING_STYLE environment variable says something else.
+    let quoting_style = if matches.get_flag("escape") {
+        QuotingStyle::Escape
+    } else if matches.get_flag("literal") {
+        QuotingStyle::Literal
+    } else if let Some(style) = matches.get_one::<String>("quoting-style") {
+        QuotingStyle::parse(style).unwrap_or(QuotingStyle::Literal)
+    } else if zero {
+        QuotingStyle::Literal
+    } else if let Some(style) = std::env::var("QUOTING_STYLE").ok()
+        .and_then(|style| QuotingStyle::parse(&style))
+    {
+        style
+    } else if is_stdout_tty() {
+        QuotingStyle::ShellEscape
+    } else {
+        QuotingStyle::Literal
+    };
+    let hide_control = if matches.get_flag("hide-control-chars") {
+        true
+    } else if matches.get_flag("show-control-chars") {
+        false
+    } else {
+        !zero && is_stdout_tty()
+    };
+    let color = matches.get_one::<String>("color");
+
+    let mut ignore = patterns(&matches, "ignore");
+    if matches.get_flag("ignore-backups") {
+        ignore.extend(["*~", ".*~"].map(|pattern| CString::new(pattern).unwrap()));
     }
 
+    let options = LsOptions {
+        long,
+        layout,
+        width: terminal_width(),
+        all: matches.get_flag("all") ||
+            (matches.get_flag("all-unsorted") && !matches.get_flag("almost-all")),
+        almost_all: matches.get_flag("almost-all"),
+        ignore,
+        hide: patterns(&matches, "hide"),
+        gitignore: matches.get_flag("gitignore"),
+        git: matches.get_flag("git"),
+        recursive: matches.get_flag("recursive"),
+        // -d lists the directories themselves, so there is no tree to draw.
+        tree: (matches.contains_id("tree") && !matches.get_flag("directory")).then(|| {
+            matches.get_one::<u64>("tree").map_or(usize::MAX, |depth| *depth as usize)
+        }),
+        dereference: matches.get_flag("dereference"),
+        numeric_ids: matches.get_flag("numeric-uid-gid"),
+        inode: matches.get_flag("inode"),
+        blocks: matches.get_flag("size"),
+        context: matches.get_flag("context"),
+        sort,
+        reverse: matches.get_flag("reverse"),
+        group_directories_first: matches.get_flag("group-directories-first"),
+        size_style,
+        colors: (use_color(color) && !(zero && color.is_none())).then(Colors::from_env),
+        directory: matches.get_flag("directory"),
+        indicators,
+        quoting_style,
+        hide_control,
+        time_field,
+        time_style,
+        json,
+        zero,
+        dired,
+        hyperlink: use_hyperlink(matches.get_one::<String>("hyperlink")),
+    };
+
     // Get positional arguments.
-    let mut files: Vec<String>;
-    files = matches.get_many::<String>("FILE")
+    let mut files: Vec<OsString>;
+    files = matches.get_many::<OsString>("FILE")
         .unwrap_or_default()
-        .map(|v| v.as_str().to_owned())
+        .cloned()
         .collect();
-    if files.len() == 0 {
-        files.push(".".to_owned());
+    if files.is_empty() {
+        files.push(OsString::from("."));
     }
 
     // only_one: we display the directory name only if we have more
-    //           than one entries on command line.
-    let only_one = files.len() == 1;
+    //           than one entries on command line, or if we are listing
+    //           recursively.
+    let only_one = files.len() == 1 && !options.recursive;
+    let mut tree_counts = TreeCounts { directories: 0, files: 0 };
+    // first: nothing has been printed yet, so the next directory header
+    //        doesn't need a blank line in front of it.
+    let mut first = true;
+    // Like GNU ls, we follow symbolic links to directories given on the
+    // command line, unless we were asked for the long format, for
+    // indicators or for the directories themselves. -L follows them all.
+    let follow_to_dir =
+        !(options.long || options.directory || options.indicators == Indicators::Classify);
+    let lister = Lister::new(options);
+    let options = lister.options();
+    let mut renderer = Renderer::new(options, io::stdout().lock());
+    renderer.begin()?;
+    let mut operands = Vec::new();
     for file in files {
-        if let Some(entry) = MyDirEntry::new(file) {
-            if entry.metadata.is_dir() {
-                if !only_one {
-                    show_directory_name(&entry)?;
-                }
-                let _ = show_directory_entries(&entry, &options);
-            } else {
-                show_file(&entry);
-            }
+        let entry = if options.dereference {
+            MyDirEntry::new(file.clone(), true)
+        } else {
+            MyDirEntry::new_operand(file.clone(), follow_to_dir)
+        };
+        match entry {
+            Ok(entry) => operands.push(entry),
+            Err(error) => report_error("cannot access", Path::new(&file), &error, EXIT_SERIOUS),
         }
     }
+    // Like GNU ls, we show the files first, all together and sorted like
+    // the contents of a directory, and then the directories.
+    lister.sort(&mut operands);
+    let (directories, files): (Vec<&MyDirEntry>, Vec<&MyDirEntry>) = operands.iter()
+        .partition(|entry| entry.is_dir() && !options.directory);
+    if !files.is_empty() {
+        renderer.show_entries(&files)?;
+        first = false;
+    }
+    for entry in directories {
+        if options.tree.is_some() {
+            renderer.show_file(entry)?;
+            show_tree(entry, &lister, &mut renderer, &mut tree_counts)?;
+        } else {
+            list_directory(entry, &lister, &mut renderer, !only_one, &mut first)?;
+        }
+    }
+    if options.tree.is_some() {
+        renderer.show_text(&format!("\n{}", tree_counts))?;
+    }
+    renderer.finish()?;
 
     Ok(())
 }
 
-fn show_directory_name(entry: &MyDirEntry) -> Result<(), Box<dyn std::error::Error>>
-{
-    println!("{}:", entry.file);
-    Ok(())
-}
-
-fn show_directory_entries(entry: &MyDirEntry, options: &LsOptions) ->
-    Result<(), Box<dyn std::error::Error>>
+// Lists the directory and, with -R, all its subdirectories, each under
+// its name unless show_name is false for the one we start from.
+fn list_directory<W: Write>(entry: &MyDirEntry, lister: &Lister, renderer: &mut Renderer<W>,
+    show_name: bool, first: &mut bool) -> io::Result<()>
 {
-    let dir_entry_list = fs::read_dir(&entry.path)?;
-    let mut entries: Vec<MyDirEntry> = Vec::new();
-    for dir_entry in dir_entry_list {
-        if let Ok(dir_entry) = dir_entry {
-            let path = dir_entry.path();
-            if let Some(entry) = MyDirEntry::new_from_path(path) {
-                entries.push(entry);
-            }
-        }
-    }
-    entries.sort_by(|a, b| a.file.to_lowercase().partial_cmp(&b.file.to_lowercase()).unwrap());
-    for entry in &entries {
-        // If file name begins with '.' and -a was not used in the command,
-        // we skip the file.
-        if let Some(file_name) = entry.path.file_name() {
-            if let Some(file_name) = file_name.to_str() {
-                if (file_name.len() > 0) && (file_name.chars().nth(0).unwrap() == '.') &&
-                    !options.all
-                {
-                    // Skip.
-                    continue;
+    let options = lister.options();
+    for item in lister.walk(entry.clone()) {
+        match item {
+            WalkItem::Directory(dir, depth) => {
+                // Each JSON object carries its own path, so there are no
+                // headers.
+                if (show_name || depth > 0) && options.json.is_none() {
+                    if !*first {
+                        renderer.show_separator()?;
+                    }
+                    renderer.show_directory_name(&dir)?;
                 }
+                *first = false;
             }
+            WalkItem::Entries(entries) => renderer.show_listing(&entries)?,
+            WalkItem::Error(error, depth) => report_walk_error(&error, depth),
         }
-
-        let _ = show_entry(entry, options);
     }
     Ok(())
 }
 
-fn show_file(entry: &MyDirEntry) {
-    if entry.metadata.is_dir() {
-        println!("{}", entry.file.blue());
-    } else if is_executable(&entry.metadata) {
-        println!("{}", entry.file.green());
-    } else {
-        println!("{}", entry.file);
+// The totals that --tree prints at the end, like tree(1) does. The
+// directories given on the command line don't count.
+struct TreeCounts {
+    directories: usize,
+    files: usize,
+}
+
+impl std::fmt::Display for TreeCounts {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}, {} {}",
+            self.directories, if self.directories == 1 { "directory" } else { "directories" },
+            self.files, if self.files == 1 { "file" } else { "files" })
     }
 }
 
-fn show_entry(entry: &MyDirEntry, options: &LsOptions) -> Result<(), Error> {
-    let metadata = &entry.metadata;
-    let permissions = metadata.permissions();
-    let mode = permissions.mode();
-    let modified = metadata.modified()?;
-    let now = Local::now();
-    let tz = now.timezone();
-    let datetime = systime_converter::convert(modified, tz);
-    if options.long {
-        println!("{} {} {} {} {} {} {}", mode_str(mode, &metadata), metadata.st_nlink(), metadata.st_uid(),
-            metadata.st_gid(), metadata.len(), datetime.format("%Y %b %e %H:%M"),
-            entry.file);
-    } else {
-        show_file(&entry);
+// Prints the entries below the directory, down to the depth given to
+// --tree. Like tree(1), we don't complain about loops; we just don't
+// follow them.
+fn show_tree<W: Write>(entry: &MyDirEntry, lister: &Lister, renderer: &mut Renderer<W>,
+    counts: &mut TreeCounts) -> io::Result<()>
+{
+    for item in lister.tree(entry.clone()) {
+        match item {
+            TreeItem::Entry(entry, lasts) => {
+                renderer.show_tree_entry(&entry, &lasts)?;
+                if entry.is_dir_or_link_to_dir() {
+                    counts.directories += 1;
+                } else {
+                    counts.files += 1;
+                }
+            }
+            TreeItem::Error(WalkError::Loop(_), _) => {}
+            TreeItem::Error(error, depth) => report_walk_error(&error, depth),
+        }
     }
     Ok(())
 }
 
-fn file_type_char(metadata: &fs::Metadata) -> char {
-    if metadata.is_dir() {
-        'd'
-    } else if metadata.is_symlink() {
-        'l'
-    } else {
-        '-'
+// Problems with a directory given on the command line are serious; below
+// it, they are minor.
+fn report_walk_error(error: &WalkError, depth: usize) {
+    let status = if depth == 0 { EXIT_SERIOUS } else { EXIT_MINOR };
+    match error {
+        WalkError::Access(path, error) => report_error("cannot access", path, error, EXIT_MINOR),
+        WalkError::Open(path, error) => report_error("cannot open directory", path, error, status),
+        WalkError::Loop(path) => {
+            eprintln!("ls: {}: not listing already-listed directory",
+                String::from_utf8_lossy(&quoting::quote(path.as_os_str(), QuotingStyle::ShellEscape, false)));
+            set_exit_status(EXIT_SERIOUS);
+        }
+        WalkError::Entry(dir, EntryError::Reading(error)) => {
+            report_error("reading directory", dir, error, status);
+        }
+        WalkError::Entry(_, EntryError::Access(path, error)) => {
+            report_error("cannot access", path, error, EXIT_MINOR);
+        }
     }
 }
 
-fn is_executable(metadata: &fs::Metadata) -> bool {
-    let permissions = metadata.permissions();
-    let mode = permissions.mode();
-    return (mode & libc::S_IXUSR) != 0;
+fn patterns(matches: &clap::ArgMatches, id: &str) -> Vec<CString> {
+    matches.get_many::<OsString>(id)
+        .unwrap_or_default()
+        .filter_map(|pattern| CString::new(pattern.as_encoded_bytes()).ok())
+        .collect()
 }
 
-fn mode_str(mode: u32, metadata: &fs::Metadata) -> String {
-    let file_type = file_type_char(metadata);
-    let mode_ur = perm_char(mode & libc::S_IRUSR, 'r');
-    let mode_uw = perm_char(mode & libc::S_IWUSR, 'w');
-    let mode_ux = perm_char(mode & libc::S_IXUSR, 'x');
-
-    let mode_gr = perm_char(mode & libc::S_IRGRP, 'r');
-    let mode_gw = perm_char(mode & libc::S_IWGRP, 'w');
-    let mode_gx = perm_char(mode & libc::S_IXGRP, 'x');
-
-    let mode_or = perm_char(mode & libc::S_IROTH, 'r');
-    let mode_ow = perm_char(mode & libc::S_IWOTH, 'w');
-    let mode_ox = perm_char(mode & libc::S_IXOTH, 'x');
+// --color with no value means always; without --color at all we color
+// only when printing to a terminal.
+fn use_color(when: Option<&String>) -> bool {
+    match when.map(|when| when.as_str()) {
+        Some("always" | "yes" | "force") => true,
+        Some("never" | "no" | "none") => false,
+        _ => is_stdout_tty(),
+    }
+}
 
-    format!("{}{}{}{}{}{}{}{}{}{}",
-        file_type,
-        mode_ur, mode_uw, mode_ux,
-        mode_gr, mode_gw, mode_gx,
-        mode_or, mode_ow, mode_ox).to_string()
+// Unlike colors, links are off unless asked for.
+fn use_hyperlink(when: Option<&String>) -> bool {
+    match when.map(|when| when.as_str()) {
+        Some("always" | "yes" | "force") => true,
+        Some("auto" | "tty" | "if-tty") => is_stdout_tty(),
+        _ => false,
+    }
+}
 
+fn is_stdout_tty() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
 }
 
-fn perm_char(flag: u32, ch: char) -> char {
-    if flag != 0 {
-        ch
-    } else {
-        '-'
+// Width of the terminal we are printing to. If stdout is not a terminal,
+// we take the COLUMNS environment variable, and the traditional 80 as the
+// last resort.
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if result == 0 && size.ws_col > 0 {
+        return size.ws_col as usize;
     }
+    std::env::var("COLUMNS").ok()
+        .and_then(|columns| columns.parse().ok())
+        .filter(|columns| *columns > 0)
+        .unwrap_or(80)
 }
@@ -0,0 +1,266 @@
+// Turning file names into something that is safe to print. A name on
+// Linux is any sequence of bytes except '/' and NUL, so it can contain
+// newlines, escape sequences or bytes that are not valid UTF-8. The
+// quoting styles are the ones of GNU ls (see --quoting-style there).
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotingStyle {
+    // The name as it is.
+    Literal,
+    // Quoted for the shell, if it needs to be.
+    Shell,
+    // Like Shell, but control characters and invalid bytes are written as
+    // $'...' strings, so the output can always be pasted back into bash.
+    ShellEscape,
+    // In double quotes, with C escape sequences.
+    C,
+    // Like C, but without the double quotes.
+    Escape,
+}
+
+impl QuotingStyle {
+    pub fn parse(style: &str) -> Option<QuotingStyle> {
+        match style {
+            "literal" => Some(QuotingStyle::Literal),
+            "shell" => Some(QuotingStyle::Shell),
+            "shell-escape" => Some(QuotingStyle::ShellEscape),
+            "c" => Some(QuotingStyle::C),
+            "escape" => Some(QuotingStyle::Escape),
+            _ => None,
+        }
+    }
+
+    // The name that parse takes, as in --quoting-style.
+    pub fn name(self) -> &'static str {
+        match self {
+            QuotingStyle::Literal => "literal",
+            QuotingStyle::Shell => "shell",
+            QuotingStyle::ShellEscape => "shell-escape",
+            QuotingStyle::C => "c",
+            QuotingStyle::Escape => "escape",
+        }
+    }
+}
+
+// A name split into valid characters and the bytes that are not UTF-8.
+enum Piece {
+    Char(char),
+    Byte(u8),
+}
+
+fn pieces(name: &OsStr) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    for chunk in name.as_bytes().utf8_chunks() {
+        pieces.extend(chunk.valid().chars().map(Piece::Char));
+        pieces.extend(chunk.invalid().iter().map(|byte| Piece::Byte(*byte)));
+    }
+    pieces
+}
+
+// The result is bytes, not a string: the literal and shell styles pass
+// bytes that are not valid UTF-8 through unchanged, as GNU ls does. With
+// hide_control (-q), those bytes and the control characters become '?'.
+pub fn quote(name: &OsStr, style: QuotingStyle, hide_control: bool) -> Vec<u8> {
+    let pieces = pieces(name);
+    match style {
+        QuotingStyle::Literal => literal(&pieces, hide_control),
+        QuotingStyle::Shell => shell(&pieces, hide_control, false),
+        QuotingStyle::ShellEscape => shell_escape(&pieces, false).into_bytes(),
+        QuotingStyle::C => format!("\"{}\"", c_escape(&pieces, true)).into_bytes(),
+        QuotingStyle::Escape => c_escape(&pieces, false).into_bytes(),
+    }
+}
+
+// For error messages: like GNU ls, we always put the name in quotes there,
+// whatever the quoting style of the listing.
+pub fn quote_always(name: &OsStr) -> String {
+    shell_escape(&pieces(name), true)
+}
+
+fn literal(pieces: &[Piece], hide_control: bool) -> Vec<u8> {
+    let mut result = Vec::new();
+    for piece in pieces {
+        match piece {
+            Piece::Char(c) if hide_control && c.is_control() => result.push(b'?'),
+            Piece::Char(c) => result.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            Piece::Byte(_) if hide_control => result.push(b'?'),
+            Piece::Byte(byte) => result.push(*byte),
+        }
+    }
+    result
+}
+
+// Characters that mean something to the shell and force us to quote. As
+// in gnulib, '#' and '~' only count at the start of a name, where they
+// begin a comment or a home directory, and '{' and '}' only on their own.
+fn is_shell_special(c: char, first: bool, alone: bool) -> bool {
+    match c {
+        '#' | '~' => first,
+        '{' | '}' => alone,
+        _ => c.is_whitespace() || c.is_control() || "!\"$&'()*;<=>?[\\^`|".contains(c),
+    }
+}
+
+// Bytes that are not valid UTF-8 mean nothing to the shell, so they don't
+// need quotes either.
+fn needs_quotes(pieces: &[Piece]) -> bool {
+    pieces.iter().enumerate().any(|(index, piece)| match piece {
+        Piece::Char(c) => is_shell_special(*c, index == 0, pieces.len() == 1),
+        Piece::Byte(_) => false,
+    })
+}
+
+// A name containing a single quote, but nothing that is special inside
+// double quotes, reads better as "it's" than as 'it'\''s'.
+fn prefers_double_quotes(pieces: &[Piece]) -> bool {
+    pieces.iter().any(|piece| matches!(piece, Piece::Char('\'')))
+        && !pieces.iter().any(|piece| match piece {
+            Piece::Char(c) => "\"$`\\!".contains(*c) || c.is_control(),
+            Piece::Byte(_) => true,
+        })
+}
+
+fn shell(pieces: &[Piece], hide_control: bool, always: bool) -> Vec<u8> {
+    let text = literal(pieces, hide_control);
+    if !always && !needs_quotes(pieces) {
+        text
+    } else if prefers_double_quotes(pieces) {
+        [b"\"", &text[..], b"\""].concat()
+    } else {
+        let mut result = vec![b'\''];
+        for byte in text {
+            match byte {
+                b'\'' => result.extend_from_slice(b"'\\''"),
+                _ => result.push(byte),
+            }
+        }
+        result.push(b'\'');
+        result
+    }
+}
+
+// Always valid UTF-8: everything that isn't a printable character goes
+// into $'...' as an escape sequence, so the lossy conversions below lose
+// nothing.
+fn shell_escape(pieces: &[Piece], always: bool) -> String {
+    let printable = |piece: &Piece| matches!(piece, Piece::Char(c) if !c.is_control());
+    if pieces.iter().all(printable) {
+        return String::from_utf8_lossy(&shell(pieces, false, always)).into_owned();
+    }
+    // Printable runs go into '...', everything else into $'...'.
+    let mut result = String::new();
+    let mut index = 0;
+    while index < pieces.len() {
+        let is_printable = printable(&pieces[index]);
+        let end = pieces[index..].iter()
+            .position(|piece| printable(piece) != is_printable)
+            .map_or(pieces.len(), |length| index + length);
+        let run = &pieces[index..end];
+        if is_printable {
+            result.push('\'');
+            result.push_str(&String::from_utf8_lossy(&literal(run, false)).replace('\'', "'\\''"));
+            result.push('\'');
+        } else {
+            result.push_str("$'");
+            result.push_str(&c_escape(run, true));
+            result.push('\'');
+        }
+        index = end;
+    }
+    result
+}
+
+// in_quotes: the result goes between double quotes (the C style), so a
+// double quote needs a backslash; without quotes (the escape style) it's
+// the spaces that do.
+fn c_escape(pieces: &[Piece], in_quotes: bool) -> String {
+    let mut result = String::new();
+    for piece in pieces {
+        match piece {
+            Piece::Char('\\') => result.push_str("\\\\"),
+            Piece::Char('"') if in_quotes => result.push_str("\\\""),
+            Piece::Char(' ') if !in_quotes => result.push_str("\\ "),
+            Piece::Char('\x07') => result.push_str("\\a"),
+            Piece::Char('\x08') => result.push_str("\\b"),
+            Piece::Char('\x0c') => result.push_str("\\f"),
+            Piece::Char('\n') => result.push_str("\\n"),
+            Piece::Char('\r') => result.push_str("\\r"),
+            Piece::Char('\t') => result.push_str("\\t"),
+            Piece::Char('\x0b') => result.push_str("\\v"),
+            Piece::Char(c) if c.is_control() => {
+                let mut buffer = [0; 4];
+                for byte in c.encode_utf8(&mut buffer).bytes() {
+                    result.push_str(&format!("\\{:03o}", byte));
+                }
+            }
+            Piece::Char(c) => result.push(*c),
+            Piece::Byte(byte) => result.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quoted(name: &[u8], style: QuotingStyle) -> String {
+        String::from_utf8(quote(OsStr::from_bytes(name), style, false)).unwrap()
+    }
+
+    #[test]
+    fn literal_leaves_names_alone() {
+        assert_eq!(quoted(b"sp ace", QuotingStyle::Literal), "sp ace");
+        assert_eq!(quoted(b"new\nline", QuotingStyle::Literal), "new\nline");
+        assert_eq!(quote(OsStr::new("new\nline"), QuotingStyle::Literal, true), b"new?line");
+    }
+
+    #[test]
+    fn only_hide_control_hides_invalid_bytes() {
+        let name = OsStr::from_bytes(b"a\xffb");
+        assert_eq!(quote(name, QuotingStyle::Literal, false), b"a\xffb");
+        assert_eq!(quote(name, QuotingStyle::Literal, true), b"a?b");
+        assert_eq!(quote(name, QuotingStyle::Shell, false), b"a\xffb");
+        assert_eq!(quote(OsStr::from_bytes(b"sp \xff"), QuotingStyle::Shell, false), b"'sp \xff'");
+        assert_eq!(quote(OsStr::from_bytes(b"sp \xff"), QuotingStyle::Shell, true), b"'sp ?'");
+    }
+
+    #[test]
+    fn shell_quotes_only_when_needed() {
+        assert_eq!(quoted(b"plain.txt", QuotingStyle::Shell), "plain.txt");
+        assert_eq!(quoted(b"sp ace", QuotingStyle::Shell), "'sp ace'");
+        assert_eq!(quoted(b"it's", QuotingStyle::Shell), "\"it's\"");
+        assert_eq!(quoted(b"it's $HOME", QuotingStyle::Shell), "'it'\\''s $HOME'");
+    }
+
+    #[test]
+    fn shell_special_characters_depend_on_their_place() {
+        assert_eq!(quoted(b"f~", QuotingStyle::Shell), "f~");
+        assert_eq!(quoted(b"~f", QuotingStyle::Shell), "'~f'");
+        assert_eq!(quoted(b"a#b", QuotingStyle::Shell), "a#b");
+        assert_eq!(quoted(b"#a", QuotingStyle::Shell), "'#a'");
+        assert_eq!(quoted(b"{}", QuotingStyle::Shell), "{}");
+        assert_eq!(quoted(b"{", QuotingStyle::Shell), "'{'");
+    }
+
+    #[test]
+    fn shell_escape_uses_dollar_quotes_for_control_characters() {
+        assert_eq!(quoted(b"new\nline", QuotingStyle::ShellEscape), "'new'$'\\n''line'");
+        assert_eq!(quoted(b"\xff", QuotingStyle::ShellEscape), "$'\\377'");
+    }
+
+    #[test]
+    fn c_and_escape_styles() {
+        assert_eq!(quoted(b"a \"b\"\t", QuotingStyle::C), "\"a \\\"b\\\"\\t\"");
+        assert_eq!(quoted(b"a \"b\"\t", QuotingStyle::Escape), "a\\ \"b\"\\t");
+        assert_eq!(quoted(b"\x01\xfe", QuotingStyle::Escape), "\\001\\376");
+    }
+
+    #[test]
+    fn error_messages_always_quote() {
+        assert_eq!(quote_always(OsStr::new("x")), "'x'");
+    }
+}
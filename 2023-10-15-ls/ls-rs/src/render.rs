@@ -0,0 +1,925 @@
+// Printing the entries that a Lister has collected: the grid, the long
+// format, JSON, and all the columns and names that go into them.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::fs::Metadata;
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use chrono::{DateTime, Local, SecondsFormat, TimeZone, Timelike};
+use serde_json::json;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use users::{Groups, Users, UsersCache};
+use unicode_width::UnicodeWidthStr;
+
+use crate::{git, hyperlink, quoting, xattr};
+use crate::{Indicators, JsonFormat, Layout, LsOptions, MyDirEntry, SizeStyle, TimeField, TimeStyle};
+
+// Writes entries to out the way the options say. What it has to remember
+// from one call to the next, for the JSON array and for --dired, it keeps
+// to itself, so every Renderer starts from scratch.
+#[derive(Debug)]
+pub struct Renderer<'a, W: Write> {
+    options: &'a LsOptions,
+    out: W,
+    // Set once the first element of the JSON array has been written;
+    // every element after that needs a comma in front of it.
+    json_started: bool,
+    dired: Dired,
+}
+
+impl<'a, W: Write> Renderer<'a, W> {
+    pub fn new(options: &'a LsOptions, out: W) -> Renderer<'a, W> {
+        Renderer { options, out, json_started: false, dired: Dired::default() }
+    }
+
+    // The writer back, for callers that render into a buffer.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    // With --format=json, all the listings go into a single array, which
+    // begin opens and finish closes.
+    pub fn begin(&mut self) -> io::Result<()> {
+        if self.options.json == Some(JsonFormat::Array) {
+            writeln!(self.out, "[")?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.options.json == Some(JsonFormat::Array) {
+            if !self.json_started {
+                writeln!(self.out, "]")?;
+            } else {
+                writeln!(self.out, "\n]")?;
+            }
+        }
+        if self.options.dired {
+            if !self.dired.names.is_empty() {
+                writeln!(self.out, "//DIRED// {}", offsets(&self.dired.names))?;
+            }
+            if !self.dired.subdirectories.is_empty() {
+                writeln!(self.out, "//SUBDIRED// {}", offsets(&self.dired.subdirectories))?;
+            }
+            writeln!(self.out, "//DIRED-OPTIONS// --quoting-style={}", self.options.quoting_style.name())?;
+        }
+        self.out.flush()
+    }
+
+    // The contents of a directory, after the "total" line where there is
+    // one.
+    pub fn show_listing(&mut self, entries: &[MyDirEntry]) -> io::Result<()> {
+        let options = self.options;
+        let entries: Vec<&MyDirEntry> = entries.iter().collect();
+        if (options.long || options.blocks) && options.json.is_none() {
+            let total = format!("{}total {}", indent(options),
+                total_size(&entries, &options.size_style)?);
+            self.write_line(total.as_bytes(), end_of_line(options))?;
+        }
+        self.show_entries(&entries)
+    }
+
+    pub fn show_entries(&mut self, entries: &[&MyDirEntry]) -> io::Result<()> {
+        let options = self.options;
+        if options.json.is_some() {
+            self.show_json(entries)
+        } else if options.long {
+            self.show_long(entries)
+        } else if options.layout == Layout::OneLine {
+            for (entry, prefix) in entries.iter().zip(name_prefixes(entries, options)?) {
+                let line = [prefix.as_bytes(), &display_name(entry, options)].concat();
+                self.write_line(&line, end_of_line(options))?;
+            }
+            Ok(())
+        } else {
+            self.show_grid(entries)
+        }
+    }
+
+    // The header in front of each directory when we list several. Even
+    // with --zero it ends with a newline, as in GNU ls.
+    pub fn show_directory_name(&mut self, dir: &MyDirEntry) -> io::Result<()> {
+        let indent = indent(self.options);
+        let name = quote(dir.path.as_os_str(), self.options);
+        let (link_start, link_end) = link_codes(dir, self.options);
+        if self.options.dired {
+            self.dired.subdirectory_at(indent.len() + link_start.len(), name.len());
+        }
+        let line = [indent.as_bytes(), link_start.as_bytes(), &name, link_end.as_bytes(), b":"].concat();
+        self.write_line(&line, b'\n')
+    }
+
+    // The empty line between two directories.
+    pub fn show_separator(&mut self) -> io::Result<()> {
+        self.write_line(b"", b'\n')
+    }
+
+    pub fn show_file(&mut self, entry: &MyDirEntry) -> io::Result<()> {
+        self.write_line(&display_name(entry, self.options), end_of_line(self.options))
+    }
+
+    // One line of --tree. lasts says, for each level down to the entry,
+    // whether the entry there is the last of its directory (see
+    // TreeItem): the levels above need a line only where more follows.
+    pub fn show_tree_entry(&mut self, entry: &MyDirEntry, lasts: &[bool]) -> io::Result<()> {
+        let link = match entry.target() {
+            Some(target) => [b" -> ", &quote(target.as_os_str(), self.options)[..]].concat(),
+            None => Vec::new(),
+        };
+        let (last, above) = lasts.split_last().unwrap_or((&true, &[]));
+        let indent: String = above.iter()
+            .map(|last| if *last { "    " } else { "│   " })
+            .collect();
+        let connector = if *last { "└── " } else { "├── " };
+        let line = [indent.as_bytes(), connector.as_bytes(), &display_name(entry, self.options),
+            &link].concat();
+        self.write_line(&line, end_of_line(self.options))
+    }
+
+    // A line of the caller's own, such as the totals at the end of --tree.
+    pub fn show_text(&mut self, text: &str) -> io::Result<()> {
+        self.write_line(text.as_bytes(), b'\n')
+    }
+
+    // Everything but JSON goes out through here, which keeps count of the
+    // bytes for --dired. Lines are bytes because names are: a name that
+    // isn't valid UTF-8 is written as it is.
+    fn write_line(&mut self, line: &[u8], end: u8) -> io::Result<()> {
+        self.out.write_all(line)?;
+        self.out.write_all(&[end])?;
+        self.dired.position += line.len() + 1;
+        Ok(())
+    }
+}
+
+// What --dired prints at the end: the byte offsets where each name in
+// the long format, and each directory name in a header, starts and ends.
+#[derive(Debug, Default)]
+struct Dired {
+    // How many bytes we have written so far.
+    position: usize,
+    names: Vec<(usize, usize)>,
+    subdirectories: Vec<(usize, usize)>,
+}
+
+impl Dired {
+    // start is where the name begins in the line about to be written.
+    fn name_at(&mut self, start: usize, len: usize) {
+        self.names.push((self.position + start, self.position + start + len));
+    }
+
+    fn subdirectory_at(&mut self, start: usize, len: usize) {
+        self.subdirectories.push((self.position + start, self.position + start + len));
+    }
+}
+
+fn offsets(spans: &[(usize, usize)]) -> String {
+    spans.iter()
+        .map(|(start, end)| format!("{} {}", start, end))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn end_of_line(options: &LsOptions) -> u8 {
+    if options.zero { b'\0' } else { b'\n' }
+}
+
+// Emacs expects the lines of --dired to be indented by two spaces.
+fn indent(options: &LsOptions) -> &'static str {
+    if options.dired { "  " } else { "" }
+}
+
+// The name as we print it: colored, and followed by its indicator.
+fn display_name(entry: &MyDirEntry, options: &LsOptions) -> Vec<u8> {
+    let mut name = colored_name(entry, options);
+    if let Some(indicator) = entry_indicator(entry, options) {
+        name.push(indicator as u8);
+    }
+    name
+}
+
+// Width of display_name on the screen, escape sequences not included.
+fn display_width(entry: &MyDirEntry, options: &LsOptions) -> usize {
+    let indicator = entry_indicator(entry, options).map_or(0, |_| 1);
+    text_width(&quote(&entry.file, options)) + indicator
+}
+
+// In terminal columns: wide characters (CJK, for example) take two and
+// combining characters none, as with wcwidth(3). A terminal shows each
+// byte that isn't part of a valid character as one replacement character.
+fn text_width(text: &[u8]) -> usize {
+    text.utf8_chunks()
+        .map(|chunk| chunk.valid().width() + chunk.invalid().len())
+        .sum()
+}
+
+// Without indicators we don't need to look at the metadata, which may not
+// have been read.
+fn entry_indicator(entry: &MyDirEntry, options: &LsOptions) -> Option<char> {
+    match options.indicators {
+        Indicators::None => None,
+        _ => indicator(entry.metadata().ok()?, options),
+    }
+}
+
+fn indicator(metadata: &Metadata, options: &LsOptions) -> Option<char> {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        return match options.indicators {
+            Indicators::None => None,
+            _ => Some('/'),
+        };
+    }
+    if options.indicators == Indicators::None || options.indicators == Indicators::Slash {
+        return None;
+    }
+    if file_type.is_symlink() {
+        Some('@')
+    } else if file_type.is_fifo() {
+        Some('|')
+    } else if file_type.is_socket() {
+        Some('=')
+    } else if file_type.is_file() && options.indicators == Indicators::Classify &&
+        is_executable(metadata)
+    {
+        Some('*')
+    } else {
+        None
+    }
+}
+
+// The link goes inside the color, as in GNU ls. Both are left out of
+// display_width.
+fn colored_name(entry: &MyDirEntry, options: &LsOptions) -> Vec<u8> {
+    let (link_start, link_end) = link_codes(entry, options);
+    let name = [link_start.as_bytes(), &quote(&entry.file, options), link_end.as_bytes()].concat();
+    match &options.colors {
+        Some(colors) => colors.paint(entry, &name),
+        None => name,
+    }
+}
+
+fn link_codes(entry: &MyDirEntry, options: &LsOptions) -> (String, &'static str) {
+    if options.hyperlink {
+        (hyperlink::start(&entry.path), hyperlink::END)
+    } else {
+        (String::new(), "")
+    }
+}
+
+fn quote(name: &OsStr, options: &LsOptions) -> Vec<u8> {
+    quoting::quote(name, options.quoting_style, options.hide_control)
+}
+
+impl<W: Write> Renderer<'_, W> {
+    fn show_json(&mut self, entries: &[&MyDirEntry]) -> io::Result<()> {
+        for entry in entries {
+            let object = entry_json(entry)?;
+            match self.options.json {
+                Some(JsonFormat::Array) => {
+                    if self.json_started {
+                        writeln!(self.out, ",")?;
+                    }
+                    self.json_started = true;
+                    write!(self.out, "  {}", object)?;
+                }
+                _ => writeln!(self.out, "{}", object)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Names and paths that aren't valid UTF-8 can't be JSON strings as they
+// are; for those we add the exact bytes next to the lossy string.
+fn entry_json(entry: &MyDirEntry) -> io::Result<serde_json::Value> {
+    let metadata = entry.metadata()?;
+    let mode = metadata.permissions().mode();
+    let time = |field| {
+        entry_time(metadata, field)
+            .and_then(local_datetime)
+            .map(|datetime| datetime.to_rfc3339_opts(SecondsFormat::Nanos, true))
+    };
+    let mut object = json!({
+        "name": entry.file.to_string_lossy(),
+        "path": entry.path.to_string_lossy(),
+        "type": file_type_name(metadata),
+        "mode": format!("{:04o}", mode & 0o7777),
+        "mode_string": mode_str(mode, metadata, None),
+        "nlink": metadata.st_nlink(),
+        "uid": metadata.st_uid(),
+        "gid": metadata.st_gid(),
+        "user": lookup_user(metadata.st_uid()),
+        "group": lookup_group(metadata.st_gid()),
+        "size": metadata.len(),
+        "mtime": time(TimeField::Modification),
+        "atime": time(TimeField::Access),
+        "ctime": time(TimeField::Change),
+        "birth": time(TimeField::Birth),
+        "inode": metadata.st_ino(),
+        "target": entry.target().map(|target| target.to_string_lossy()),
+    });
+    if entry.file.to_str().is_none() {
+        object["name_bytes"] = json!(entry.file.as_encoded_bytes());
+    }
+    if entry.path.to_str().is_none() {
+        object["path_bytes"] = json!(entry.path.as_os_str().as_encoded_bytes());
+    }
+    Ok(object)
+}
+
+fn file_type_name(metadata: &Metadata) -> &'static str {
+    match file_type_char(metadata) {
+        'd' => "directory",
+        'l' => "symlink",
+        'b' => "block_device",
+        'c' => "char_device",
+        'p' => "fifo",
+        's' => "socket",
+        _ => "file",
+    }
+}
+
+// Names in the grid are separated by at least two spaces.
+const COLUMN_GAP: usize = 2;
+
+impl<W: Write> Renderer<'_, W> {
+    // Like GNU ls, we look for the largest number of columns that still fits
+    // into the terminal width. With -C the entries run down the columns, with
+    // -x they run across the rows.
+    fn show_grid(&mut self, entries: &[&MyDirEntry]) -> io::Result<()> {
+        let options = self.options;
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let prefixes = name_prefixes(entries, options)?;
+        let widths: Vec<usize> = entries.iter().zip(&prefixes)
+            .map(|(entry, prefix)| prefix.len() + display_width(entry, options))
+            .collect();
+        // Every column needs at least one character plus the gap, which gives
+        // us an upper bound on the number of columns worth trying.
+        let max_columns = (options.width / (1 + COLUMN_GAP)).clamp(1, entries.len());
+        let mut columns = 1;
+        let mut column_widths = vec![widths.iter().copied().max().unwrap_or(0)];
+        for try_columns in (2..=max_columns).rev() {
+            let rows = entries.len().div_ceil(try_columns);
+            let try_widths = grid_column_widths(&widths, rows, try_columns, &options.layout);
+            // Like GNU ls, we count every column as at least one character
+            // plus the gap wide, the last one too.
+            let last = try_widths[try_widths.len() - 1];
+            let total: usize = try_widths.iter().sum::<usize>() + COLUMN_GAP * (try_widths.len() - 1)
+                + (1 + COLUMN_GAP).saturating_sub(last);
+            if total < options.width {
+                columns = try_widths.len();
+                column_widths = try_widths;
+                break;
+            }
+        }
+        let rows = entries.len().div_ceil(columns);
+        for row in 0..rows {
+            let mut line = Vec::new();
+            for (column, column_width) in column_widths.iter().enumerate() {
+                let Some(index) = grid_index(row, column, rows, columns, &options.layout) else {
+                    continue;
+                };
+                if index >= entries.len() {
+                    continue;
+                }
+                if column > 0 {
+                    line.extend_from_slice(" ".repeat(COLUMN_GAP).as_bytes());
+                }
+                line.extend_from_slice(prefixes[index].as_bytes());
+                line.extend_from_slice(&display_name(entries[index], options));
+                // No padding after the last name on the line.
+                let next = grid_index(row, column + 1, rows, columns, &options.layout);
+                if next.is_some_and(|next| next < entries.len()) {
+                    line.extend_from_slice(" ".repeat(column_width - widths[index]).as_bytes());
+                }
+            }
+            self.write_line(&line, end_of_line(options))?;
+        }
+        Ok(())
+    }
+}
+
+// The columns of -i, -s and -Z for the short formats, right-aligned (the
+// context too, unlike in the long format), so that the names still line
+// up.
+fn name_prefixes(entries: &[&MyDirEntry], options: &LsOptions) -> io::Result<Vec<String>> {
+    if !(options.inode || options.blocks || options.context) {
+        return Ok(vec![String::new(); entries.len()]);
+    }
+    let columns = entries.iter()
+        .map(|entry| {
+            let metadata = entry.metadata()?;
+            Ok((metadata.st_ino().to_string(), allocated_size(metadata, &options.size_style),
+                if options.context { context_label(entry) } else { String::new() }))
+        })
+        .collect::<io::Result<Vec<(String, String, String)>>>()?;
+    let inode_width = columns.iter().map(|(inode, _, _)| inode.len()).max().unwrap_or(0);
+    let blocks_width = columns.iter().map(|(_, blocks, _)| blocks.len()).max().unwrap_or(0);
+    let context_width = columns.iter()
+        .map(|(_, _, context)| context.chars().count())
+        .max()
+        .unwrap_or(0);
+    Ok(columns.iter().map(|(inode, blocks, context)| {
+        let mut prefix = String::new();
+        if options.inode {
+            prefix.push_str(&format!("{:>inode_width$} ", inode));
+        }
+        if options.blocks {
+            prefix.push_str(&format!("{:>blocks_width$} ", blocks));
+        }
+        if options.context {
+            prefix.push_str(&format!("{:>context_width$} ", context));
+        }
+        prefix
+    }).collect())
+}
+
+// Which entry goes into the given cell of the grid.
+fn grid_index(row: usize, column: usize, rows: usize, columns: usize, layout: &Layout) -> Option<usize> {
+    if column >= columns {
+        return None;
+    }
+    match layout {
+        Layout::Across => Some(row * columns + column),
+        _ => Some(column * rows + row),
+    }
+}
+
+// Width of each non-empty column when the entries are laid out in the
+// given number of rows and columns.
+fn grid_column_widths(widths: &[usize], rows: usize, columns: usize, layout: &Layout) -> Vec<usize> {
+    let mut column_widths = vec![0; columns];
+    for (index, width) in widths.iter().enumerate() {
+        let column = match layout {
+            Layout::Across => index % columns,
+            _ => index / rows,
+        };
+        column_widths[column] = column_widths[column].max(*width);
+    }
+    while column_widths.last() == Some(&0) {
+        column_widths.pop();
+    }
+    column_widths
+}
+
+// One line of the long format with every column already turned into text,
+// so that we can measure all the columns before printing anything.
+struct LongRow<'a> {
+    inode: String,
+    blocks: String,
+    // The ten characters of mode_str, plus '+', '.' or '@' for files with
+    // an ACL, a security context or other extended attributes.
+    mode: String,
+    nlink: String,
+    owner: String,
+    group: String,
+    context: String,
+    size: String,
+    // Block and character devices have no size; we print their major and
+    // minor device numbers instead.
+    device: Option<(String, String)>,
+    date: String,
+    git: String,
+    entry: &'a MyDirEntry,
+}
+
+impl<'a> LongRow<'a> {
+    fn new(entry: &'a MyDirEntry, options: &LsOptions) -> io::Result<LongRow<'a>> {
+        let metadata = entry.metadata()?;
+        let permissions = metadata.permissions();
+        let mode = permissions.mode();
+        let file_type = metadata.file_type();
+        let device = if file_type.is_block_device() || file_type.is_char_device() {
+            let (major, minor) = device_numbers(metadata.st_rdev());
+            Some((major.to_string(), minor.to_string()))
+        } else {
+            None
+        };
+        Ok(LongRow {
+            inode: metadata.st_ino().to_string(),
+            blocks: allocated_size(metadata, &options.size_style),
+            mode: mode_str(mode, metadata, xattr::indicator(&entry.attribute_names())),
+            nlink: metadata.st_nlink().to_string(),
+            owner: user_name(metadata.st_uid(), options),
+            group: group_name(metadata.st_gid(), options),
+            context: if options.context { context_label(entry) } else { String::new() },
+            size: format_size(metadata.len(), &options.size_style),
+            device,
+            date: format_time(entry_time(metadata, options.time_field), &options.time_style),
+            git: if options.git { git::status(&entry.path, metadata.is_dir()) } else { String::new() },
+            entry,
+        })
+    }
+}
+
+impl<W: Write> Renderer<'_, W> {
+    // Like coreutils, we right-align the numbers and left-align the names.
+    fn show_long(&mut self, entries: &[&MyDirEntry]) -> io::Result<()> {
+        let options = self.options;
+        let rows: Vec<LongRow> = entries.iter()
+            .map(|entry| LongRow::new(entry, options))
+            .collect::<io::Result<_>>()?;
+        let inode_width = column_width(&rows, |row| row.inode.len());
+        let blocks_width = column_width(&rows, |row| row.blocks.chars().count());
+        let nlink_width = column_width(&rows, |row| row.nlink.chars().count());
+        let owner_width = column_width(&rows, |row| row.owner.chars().count());
+        let group_width = column_width(&rows, |row| row.group.chars().count());
+        let mode_width = column_width(&rows, |row| row.mode.len());
+        let context_width = column_width(&rows, |row| row.context.chars().count());
+        let major_width = column_width(&rows, |row| row.device.as_ref().map_or(0, |d| d.0.len()));
+        let minor_width = column_width(&rows, |row| row.device.as_ref().map_or(0, |d| d.1.len()));
+        let mut size_width = column_width(&rows, |row| {
+            if row.device.is_some() { 0 } else { row.size.chars().count() }
+        });
+        if rows.iter().any(|row| row.device.is_some()) {
+            size_width = size_width.max(major_width + 2 + minor_width);
+        }
+        for row in &rows {
+            let size = match &row.device {
+                Some((major, minor)) => format!("{:>major_width$}, {:>minor_width$}", major, minor),
+                None => row.size.clone(),
+            };
+            // The name on its own, for --dired to point at; the color goes
+            // around it. For a symbolic link, the indicator goes after the
+            // target and describes the file the link points to.
+            let name = quote(&row.entry.file, options);
+            let (color_start, color_end) = options.colors.as_ref()
+                .and_then(|colors| colors.codes(row.entry))
+                .unwrap_or_default();
+            let (link_start, link_end) = link_codes(row.entry, options);
+            let after = match (row.entry.target(), row.entry.target_metadata()) {
+                (Some(target), target_metadata) => {
+                    let indicator = target_metadata.and_then(|m| indicator(m, options));
+                    // The link itself already leads to where it points.
+                    let mut target = quote(target.as_os_str(), options);
+                    if options.hyperlink && target_metadata.is_some() {
+                        target = hyperlink::wrap(&row.entry.path, &target);
+                    }
+                    let indicator = indicator.map(String::from).unwrap_or_default();
+                    [link_end.as_bytes(), color_end.as_bytes(), b" -> ", &target,
+                        indicator.as_bytes()].concat()
+                }
+                _ => {
+                    let indicator = entry_indicator(row.entry, options).map(String::from)
+                        .unwrap_or_default();
+                    [link_end.as_bytes(), color_end.as_bytes(), indicator.as_bytes()].concat()
+                }
+            };
+            // With --gitignore -a, a column of '!' (as in git status) tells
+            // the ignored files apart. The columns of -i and -s come next.
+            let mut prefix = match (options.gitignore && options.all, row.entry.ignored) {
+                (false, _) => String::new(),
+                (true, true) => "! ".to_owned(),
+                (true, false) => "  ".to_owned(),
+            };
+            if options.inode {
+                prefix.push_str(&format!("{:>inode_width$} ", row.inode));
+            }
+            if options.blocks {
+                prefix.push_str(&format!("{:>blocks_width$} ", row.blocks));
+            }
+            // -Z puts the security context between the group and the size.
+            let mut group = format!("{:<group_width$}", row.group);
+            if options.context {
+                group.push_str(&format!(" {:<context_width$}", row.context));
+            }
+            let mut date = row.date.clone();
+            if options.git {
+                date.push(' ');
+                date.push_str(&row.git);
+            }
+            let before = format!("{}{}{:<mode_width$} {:>nlink_width$} {:<owner_width$} {} {:>size_width$} {} {}{}",
+                indent(options), prefix, row.mode, row.nlink, row.owner, group, size, date,
+                color_start, link_start);
+            if options.dired {
+                self.dired.name_at(before.len(), name.len());
+            }
+            self.write_line(&[before.as_bytes(), &name, &after].concat(), end_of_line(options))?;
+        }
+        Ok(())
+    }
+}
+
+// Not every file system records the birth time; for those we return None
+// and show a dash, like GNU ls.
+pub(crate) fn entry_time(metadata: &Metadata, field: TimeField) -> Option<SystemTime> {
+    match field {
+        TimeField::Modification => metadata.modified().ok(),
+        TimeField::Access => metadata.accessed().ok(),
+        TimeField::Birth => metadata.created().ok(),
+        TimeField::Change => {
+            let seconds = metadata.st_ctime();
+            let nanoseconds = Duration::from_nanos(metadata.st_ctime_nsec() as u64);
+            if seconds >= 0 {
+                Some(UNIX_EPOCH + Duration::from_secs(seconds as u64) + nanoseconds)
+            } else {
+                Some(UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs()) + nanoseconds)
+            }
+        }
+    }
+}
+
+// Half of an average Gregorian year, the way coreutils defines "six
+// months ago".
+const SIX_MONTHS: Duration = Duration::from_secs(31556952 / 2);
+
+fn format_time(time: Option<SystemTime>, style: &TimeStyle) -> String {
+    let Some(time) = time else {
+        return "-".to_owned();
+    };
+    let now = SystemTime::now();
+    let recent = time <= now && now.duration_since(time).is_ok_and(|age| age < SIX_MONTHS);
+    let Some(datetime) = local_datetime(time) else {
+        return "-".to_owned();
+    };
+    let format = if recent { &style.recent } else { &style.old };
+    datetime.format(format).to_string()
+}
+
+// systime_converter keeps only the whole seconds, so we put the
+// nanoseconds back for full-iso. It also can't handle times before 1970,
+// which chrono has to do on its own.
+fn local_datetime(time: SystemTime) -> Option<DateTime<Local>> {
+    let tz = Local::now().timezone();
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => {
+            systime_converter::convert(time, tz).with_nanosecond(since_epoch.subsec_nanos())
+        }
+        Err(error) => {
+            let before_epoch = error.duration();
+            let mut seconds = -(before_epoch.as_secs() as i64);
+            let mut nanoseconds = before_epoch.subsec_nanos();
+            if nanoseconds > 0 {
+                seconds -= 1;
+                nanoseconds = 1_000_000_000 - nanoseconds;
+            }
+            tz.timestamp_opt(seconds, nanoseconds).single()
+        }
+    }
+}
+
+fn column_width<F: Fn(&LongRow) -> usize>(rows: &[LongRow], column: F) -> usize {
+    rows.iter().map(column).max().unwrap_or(0)
+}
+
+// The "total" line counts the disk space used by the listed files, by
+// default in 1024-byte blocks. st_blocks is always in 512-byte units.
+fn total_size(entries: &[&MyDirEntry], size_style: &SizeStyle) -> io::Result<String> {
+    let mut blocks = 0;
+    for entry in entries {
+        blocks += entry.metadata()?.st_blocks();
+    }
+    Ok(format_blocks(blocks, size_style))
+}
+
+// For -s: the disk space of a single file, in the units of the total.
+fn allocated_size(metadata: &Metadata, size_style: &SizeStyle) -> String {
+    format_blocks(metadata.st_blocks(), size_style)
+}
+
+fn format_blocks(blocks: u64, size_style: &SizeStyle) -> String {
+    match size_style {
+        SizeStyle::Default => blocks.div_ceil(2).to_string(),
+        _ => format_size(blocks * 512, size_style),
+    }
+}
+
+fn format_size(bytes: u64, size_style: &SizeStyle) -> String {
+    match size_style {
+        SizeStyle::Default => bytes.to_string(),
+        SizeStyle::Human(base) => human_size(bytes, *base),
+        SizeStyle::Blocks(block_size, suffix) => {
+            format!("{}{}", bytes.div_ceil(*block_size), suffix)
+        }
+    }
+}
+
+// Like coreutils, we always round up, and show one decimal for values
+// below 10: 1023 bytes is "1023", 1025 bytes is "1.1K".
+fn human_size(bytes: u64, base: u64) -> String {
+    let units: [&str; 8] = if base == 1000 {
+        ["k", "M", "G", "T", "P", "E", "Z", "Y"]
+    } else {
+        ["K", "M", "G", "T", "P", "E", "Z", "Y"]
+    };
+    if bytes < base {
+        return bytes.to_string();
+    }
+    let base = base as f64;
+    let mut value = bytes as f64 / base;
+    let mut unit = 0;
+    while value >= base && unit < units.len() - 1 {
+        value /= base;
+        unit += 1;
+    }
+    if value < 10.0 {
+        let rounded = (value * 10.0).ceil() / 10.0;
+        if rounded < 10.0 {
+            return format!("{:.1}{}", rounded, units[unit]);
+        }
+    }
+    let rounded = value.ceil();
+    if rounded >= base && unit < units.len() - 1 {
+        return format!("1.0{}", units[unit + 1]);
+    }
+    format!("{}{}", rounded, units[unit])
+}
+
+thread_local! {
+    // UsersCache remembers every uid and gid it has looked up (including
+    // the ones that don't exist), so that a directory full of files owned
+    // by the same user costs us a single getpwuid call.
+    static USERS: UsersCache = UsersCache::new();
+}
+
+// Owner name for the long format; with -n, or if the uid has no entry in
+// the password database, we fall back to the number.
+fn user_name(uid: u32, options: &LsOptions) -> String {
+    if options.numeric_ids {
+        return uid.to_string();
+    }
+    lookup_user(uid).unwrap_or_else(|| uid.to_string())
+}
+
+fn group_name(gid: u32, options: &LsOptions) -> String {
+    if options.numeric_ids {
+        return gid.to_string();
+    }
+    lookup_group(gid).unwrap_or_else(|| gid.to_string())
+}
+
+fn lookup_user(uid: u32) -> Option<String> {
+    USERS.with(|users| {
+        users.get_user_by_uid(uid).map(|user| user.name().to_string_lossy().into_owned())
+    })
+}
+
+fn lookup_group(gid: u32) -> Option<String> {
+    USERS.with(|users| {
+        users.get_group_by_gid(gid).map(|group| group.name().to_string_lossy().into_owned())
+    })
+}
+
+fn file_type_char(metadata: &fs::Metadata) -> char {
+    let file_type = metadata.file_type();
+    if file_type.is_dir() {
+        'd'
+    } else if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_block_device() {
+        'b'
+    } else if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_fifo() {
+        'p'
+    } else if file_type.is_socket() {
+        's'
+    } else {
+        '-'
+    }
+}
+
+// Splits st_rdev into the major and minor device number, the same way as
+// glibc's major() and minor() macros do. Both are 32-bit numbers, so
+// the masks for the high parts stop at bit 31.
+fn device_numbers(rdev: u64) -> (u64, u64) {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & 0xfffff000);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & 0xffffff00);
+    (major, minor)
+}
+
+pub(crate) fn is_executable(metadata: &fs::Metadata) -> bool {
+    let permissions = metadata.permissions();
+    let mode = permissions.mode();
+    (mode & libc::S_IXUSR) != 0
+}
+
+// What -Z shows for a file: its SELinux label, or '?' without one.
+fn context_label(entry: &MyDirEntry) -> String {
+    entry.security_context().unwrap_or_else(|| "?".to_owned())
+}
+
+// attributes: the character that the long format appends for extended
+// attributes, see xattr::indicator.
+fn mode_str(mode: u32, metadata: &fs::Metadata, attributes: Option<char>) -> String {
+    let file_type = file_type_char(metadata);
+    let mode_ur = perm_char(mode & libc::S_IRUSR, 'r');
+    let mode_uw = perm_char(mode & libc::S_IWUSR, 'w');
+    let mode_ux = special_char(mode & libc::S_IXUSR, mode & libc::S_ISUID, 's');
+
+    let mode_gr = perm_char(mode & libc::S_IRGRP, 'r');
+    let mode_gw = perm_char(mode & libc::S_IWGRP, 'w');
+    let mode_gx = special_char(mode & libc::S_IXGRP, mode & libc::S_ISGID, 's');
+
+    let mode_or = perm_char(mode & libc::S_IROTH, 'r');
+    let mode_ow = perm_char(mode & libc::S_IWOTH, 'w');
+    let mode_ox = special_char(mode & libc::S_IXOTH, mode & libc::S_ISVTX, 't');
+
+    format!("{}{}{}{}{}{}{}{}{}{}{}",
+        file_type,
+        mode_ur, mode_uw, mode_ux,
+        mode_gr, mode_gw, mode_gx,
+        mode_or, mode_ow, mode_ox,
+        attributes.map(String::from).unwrap_or_default())
+
+}
+
+fn perm_char(flag: u32, ch: char) -> char {
+    if flag != 0 {
+        ch
+    } else {
+        '-'
+    }
+}
+
+// The setuid, setgid and sticky bits share the execute slots: lowercase
+// s or t when the execute bit is also set, uppercase S or T when it isn't.
+fn special_char(exec_flag: u32, special_flag: u32, ch: char) -> char {
+    match (exec_flag != 0, special_flag != 0) {
+        (true, true) => ch,
+        (false, true) => ch.to_ascii_uppercase(),
+        (true, false) => 'x',
+        (false, false) => '-',
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn human_sizes_round_up() {
+        assert_eq!(human_size(1023, 1024), "1023");
+        assert_eq!(human_size(1024, 1024), "1.0K");
+        assert_eq!(human_size(1025, 1024), "1.1K");
+        assert_eq!(human_size(10239, 1024), "10K");
+        assert_eq!(human_size(1024 * 1024 - 1, 1024), "1.0M");
+        assert_eq!(human_size(1000, 1000), "1.0k");
+        assert_eq!(human_size(123_456_789, 1000), "124M");
+    }
+
+    #[test]
+    fn device_numbers_like_glibc() {
+        assert_eq!(device_numbers(libc::makedev(1, 3)), (1, 3));
+        assert_eq!(device_numbers(libc::makedev(259, 65537)), (259, 65537));
+        assert_eq!(device_numbers(libc::makedev(4096, 5)), (4096, 5));
+    }
+
+    fn render(options: &LsOptions, show: impl Fn(&mut Renderer<Vec<u8>>) -> io::Result<()>) -> String {
+        let mut renderer = Renderer::new(options, Vec::new());
+        show(&mut renderer).unwrap();
+        String::from_utf8(renderer.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn text_width_counts_terminal_columns() {
+        assert_eq!(text_width(b"plain"), 5);
+        assert_eq!(text_width("日本語".as_bytes()), 6);
+        assert_eq!(text_width("e\u{301}".as_bytes()), 1);
+        assert_eq!(text_width(b"a\xffb"), 3);
+    }
+
+    #[test]
+    fn each_renderer_starts_its_own_json_array() {
+        let options = LsOptions { json: Some(JsonFormat::Array), ..LsOptions::default() };
+        let root = MyDirEntry::new("/".into(), false).unwrap();
+        let show = |renderer: &mut Renderer<Vec<u8>>| {
+            renderer.begin()?;
+            renderer.show_entries(&[&root])?;
+            renderer.finish()
+        };
+        let first = render(&options, show);
+        let second = render(&options, show);
+        assert!(first.starts_with("[\n  {"), "{}", first);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn each_renderer_counts_its_own_dired_offsets() {
+        let options = LsOptions { long: true, dired: true, ..LsOptions::default() };
+        let tmp = MyDirEntry::new("/tmp".into(), false).unwrap();
+        let show = |renderer: &mut Renderer<Vec<u8>>| {
+            renderer.show_directory_name(&tmp)?;
+            renderer.finish()
+        };
+        let expected = "  /tmp:\n//SUBDIRED// 2 6\n//DIRED-OPTIONS// --quoting-style=literal\n";
+        assert_eq!(render(&options, show), expected);
+        assert_eq!(render(&options, show), expected);
+    }
+
+    #[test]
+    fn names_that_are_not_utf8_are_written_as_they_are() {
+        let options = LsOptions { layout: Layout::OneLine, zero: true, ..LsOptions::default() };
+        let path = std::path::PathBuf::from(OsStr::from_bytes(b"/nonexistent/\xff\xfe"));
+        let entry = MyDirEntry::without_metadata(path, None, false);
+        let mut renderer = Renderer::new(&options, Vec::new());
+        renderer.show_entries(&[&entry]).unwrap();
+        assert_eq!(renderer.into_inner(), b"\xff\xfe\0");
+    }
+}
@@ -0,0 +1,99 @@
+// Extended attributes, through listxattr(2) and getxattr(2). POSIX ACLs
+// and SELinux labels are stored in them too, so this is all we need for
+// the '+', '.' and '@' after the mode and for -Z.
+//
+// follow: whether to look at the file a symbolic link points to (the
+// plain calls) or at the link itself (the l* calls).
+
+use std::ffi::{CString, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
+
+const ACL_ACCESS: &[u8] = b"system.posix_acl_access";
+const ACL_DEFAULT: &[u8] = b"system.posix_acl_default";
+const SELINUX: &str = "security.selinux";
+
+// The names of the file's attributes. A file system without support for
+// them, or a file we can't read them from, simply has none.
+pub fn list(path: &Path, follow: bool) -> Vec<OsString> {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return Vec::new();
+    };
+    let call = |buffer: *mut libc::c_char, size: usize| unsafe {
+        if follow {
+            libc::listxattr(path.as_ptr(), buffer, size)
+        } else {
+            libc::llistxattr(path.as_ptr(), buffer, size)
+        }
+    };
+    let Some(names) = read_value(call) else {
+        return Vec::new();
+    };
+    // The names come one after the other, each ending with a NUL.
+    names.split(|byte| *byte == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| OsString::from_vec(name.to_vec()))
+        .collect()
+}
+
+pub fn get(path: &Path, name: &str, follow: bool) -> Option<Vec<u8>> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let name = CString::new(name).ok()?;
+    read_value(|buffer: *mut libc::c_char, size: usize| unsafe {
+        if follow {
+            libc::getxattr(path.as_ptr(), name.as_ptr(), buffer.cast(), size)
+        } else {
+            libc::lgetxattr(path.as_ptr(), name.as_ptr(), buffer.cast(), size)
+        }
+    })
+}
+
+// The character that ls appends to the mode: '+' for an ACL, '.' for a
+// security context, '@' for any other attribute.
+pub fn indicator(names: &[OsString]) -> Option<char> {
+    let has = |wanted: &[u8]| names.iter().any(|name| name.as_bytes() == wanted);
+    if has(ACL_ACCESS) || has(ACL_DEFAULT) {
+        Some('+')
+    } else if has(SELINUX.as_bytes()) {
+        Some('.')
+    } else if !names.is_empty() {
+        Some('@')
+    } else {
+        None
+    }
+}
+
+// The SELinux label for -Z, without the NUL at the end.
+pub fn security_context(path: &Path, follow: bool) -> Option<String> {
+    let mut value = get(path, SELINUX, follow)?;
+    while value.last() == Some(&0) {
+        value.pop();
+    }
+    Some(String::from_utf8_lossy(&value).into_owned())
+}
+
+// Called with a null buffer, the *xattr calls return the size we need. The
+// value may grow between the two calls, in which case we try again.
+fn read_value<F>(call: F) -> Option<Vec<u8>>
+    where F: Fn(*mut libc::c_char, usize) -> libc::ssize_t
+{
+    loop {
+        let size = call(std::ptr::null_mut(), 0);
+        if size < 0 {
+            return None;
+        }
+        let mut buffer = vec![0u8; size as usize];
+        let read = call(buffer.as_mut_ptr().cast(), buffer.len());
+        if read >= 0 {
+            buffer.truncate(read as usize);
+            return Some(buffer);
+        }
+        if io_error() != libc::ERANGE {
+            return None;
+        }
+    }
+}
+
+fn io_error() -> i32 {
+    std::io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}